@@ -0,0 +1,21 @@
+//! Country and city of hop addresses from a MaxMind GeoLite2/GeoIP2 City database.
+
+use maxminddb::{geoip2, Reader};
+use std::net::IpAddr;
+
+/// Load the database at `path` into memory and return a lookup for DnsResolver::location
+pub fn open(path: &str) -> Result<impl Fn(IpAddr) -> Option<String> + Send + Sync + 'static, String> {
+    let reader = Reader::open_readfile(path).map_err(|e| format!("cannot open GeoIP database {}: {}", path, e))?;
+    Ok(move |ip| reader.lookup::<geoip2::City>(ip).ok().and_then(|city| describe(&city)))
+}
+
+/// "US, Mountain View" from the country code and English city name, whichever are known
+fn describe(city: &geoip2::City) -> Option<String> {
+    let country = city.country.as_ref().and_then(|country| country.iso_code);
+    let name = city.city.as_ref().and_then(|city| city.names.as_ref()).and_then(|names| names.get("en").copied());
+    match (country, name) {
+        (Some(country), Some(name)) => Some(format!("{}, {}", country, name)),
+        (Some(only), None) | (None, Some(only)) => Some(only.to_string()),
+        (None, None) => None,
+    }
+}
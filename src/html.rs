@@ -0,0 +1,83 @@
+//! Single-file HTML report: the hop table with an inline SVG chart of each hop's recent RTTs.
+
+use mtr::{us_to_ms, HopStats, Tracer};
+
+/// Probe outcomes kept per hop for the charts
+pub const CHART_SAMPLES: usize = 600;
+
+const CHART_WIDTH: f64 = 360.0;
+const CHART_HEIGHT: f64 = 40.0;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}\
+table{border-collapse:collapse}\
+th,td{padding:2px 8px;text-align:right;border-bottom:1px solid #ddd}\
+td.host{text-align:left}\
+polyline{fill:none;stroke:#2a6fdb;stroke-width:1.5}\
+line.lost{stroke:#d33}";
+
+/// A complete HTML document with one table per trace, stamped with `generated`
+pub fn render(traces: &[Tracer], generated: &str) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>mtr report</title>\n<style>{}</style>\n</head>\n<body>\n<p>Generated {}</p>\n",
+        STYLE, escape(generated)
+    );
+    for trace in traces {
+        out.push_str(&format!("<h2>mtr to {} ({})</h2>\n<table>\n", escape(&trace.target), trace.target_ip));
+        out.push_str("<tr><th>Hop</th><th>Host</th><th>Loss%</th><th>Snt</th><th>Last</th><th>Avg</th><th>Best</th><th>Wrst</th><th>StDev</th><th>RTT</th></tr>\n");
+        for hop in trace.visible_hops() { out.push_str(&row(&hop)); }
+        out.push_str("</table>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// One table row: the hop's statistics and its chart
+pub fn row(hop: &HopStats) -> String {
+    let ms = |v: Option<u32>| v.map_or("-".to_string(), |v| format!("{:.1}", us_to_ms(v)));
+    let fixed = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{:.1}", v));
+    // An --annotations label is the user's own name for the hop, so it wins over reverse DNS
+    let host = match (hop.ip, hop.label.as_ref().or(hop.hostname.as_ref())) {
+        (Some(ip), Some(name)) => format!("{} ({})", escape(name), ip),
+        (Some(ip), None) => ip.to_string(),
+        (None, _) => "???".to_string(),
+    };
+    let avg = fixed((hop.received > 0).then(|| hop.avg_rtt()));
+    let std_dev = fixed((hop.received > 1).then(|| hop.std_dev()));
+    format!(
+        "<tr><td>{}</td><td class=\"host\">{}</td><td>{:.1}%</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+        hop.ttl, host, hop.loss_percent(), hop.sent, ms(hop.last_rtt), avg, ms(hop.min_rtt), ms(hop.max_rtt), std_dev, chart(hop)
+    )
+}
+
+/// RTT over the retained probes, scaled from 0 to the worst of them; a lost probe breaks the line with a red tick
+pub fn chart(hop: &HopStats) -> String {
+    let step = if hop.history.len() > 1 { CHART_WIDTH / (hop.history.len() - 1) as f64 } else { 0.0 };
+    // Not max_rtt, which under --window can have forgotten RTTs still plotted here
+    let top = hop.history.iter().filter_map(|sample| sample.rtt()).max().unwrap_or(0).max(1) as f64;
+    let mut svg = format!("<svg width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">", CHART_WIDTH, CHART_HEIGHT);
+    let mut points: Vec<String> = Vec::new();
+    for (i, sample) in hop.history.iter().enumerate() {
+        let x = i as f64 * step;
+        match sample.rtt() {
+            Some(rtt) => points.push(format!("{:.1},{:.1}", x, CHART_HEIGHT * (1.0 - rtt as f64 / top))),
+            None => {
+                polyline(&mut svg, &mut points);
+                svg.push_str(&format!("<line class=\"lost\" x1=\"{0:.1}\" y1=\"0\" x2=\"{0:.1}\" y2=\"{1}\"/>", x, CHART_HEIGHT));
+            }
+        }
+    }
+    polyline(&mut svg, &mut points);
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Append the pending run of points as one line segment
+fn polyline(svg: &mut String, points: &mut Vec<String>) {
+    if !points.is_empty() { svg.push_str(&format!("<polyline points=\"{}\"/>", points.join(" "))); }
+    points.clear();
+}
+
+/// Escape text for HTML element content and attributes
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
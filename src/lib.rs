@@ -0,0 +1,1388 @@
+//! Probing engine behind the `mtr` binary: per-hop statistics, ICMP probing on
+//! reusable pingers, background reverse DNS and a [`Tracer`] that runs cycles.
+
+use clap::ValueEnum;
+use dns_lookup::lookup_host;
+use hickory_resolver::Resolver;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use winping::{AsyncPinger, AsyncResult, Buffer, CreateError, Error as PingError, IpPair, PingFuture, Pinger};
+
+/// Address family restriction for target resolution
+#[derive(Clone, Copy, PartialEq)]
+pub enum Family {
+    Any,
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn allows(self, ip: IpAddr) -> bool {
+        match self {
+            Family::Any => true,
+            Family::V4 => ip.is_ipv4(),
+            Family::V6 => ip.is_ipv6(),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Family::Any => "IP",
+            Family::V4 => "IPv4",
+            Family::V6 => "IPv6",
+        }
+    }
+}
+
+/// Loss accounting for probes answered with an ICMP error instead of a reply
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ErrorPolicy {
+    /// The hop answered, so the probe counts as received with its RTT
+    Responded,
+    /// No echo reply came back, so the probe counts as lost
+    Lost,
+}
+
+/// Statistics for a single hop; RTTs are stored in microseconds
+#[derive(Clone)]
+pub struct HopStats {
+    pub ttl: u8,
+    /// Primary responder: the address seen most often at this TTL
+    pub ip: Option<IpAddr>,
+    pub hostname: Option<String>,
+    /// Origin AS of the primary responder, e.g. "AS15169"
+    pub asn: Option<String>,
+    /// Country and city of the primary responder, e.g. "US, Mountain View"
+    pub location: Option<String>,
+    /// User-supplied name of the primary responder, shown in place of its PTR name
+    pub label: Option<String>,
+    /// Every responder seen at this TTL with its count, in first-seen order
+    pub ips: Vec<(IpAddr, u32)>,
+    pub sent: u32,
+    pub received: u32,
+    pub last_rtt: Option<u32>,
+    /// Whether the most recent probe was answered, so last_rtt belongs to it
+    pub replied: bool,
+    pub min_rtt: Option<u32>,
+    pub max_rtt: Option<u32>,
+    /// Running mean of the RTT (Welford)
+    mean_rtt: f64,
+    /// Running sum of squared deviations from the mean (Welford)
+    m2_rtt: f64,
+    /// Sum of absolute differences between consecutive reply RTTs
+    sum_jitter: u64,
+    /// RTT of the previous reply, the other end of the next jitter delta
+    prev_reply: Option<u32>,
+    /// Latest and largest jitter delta
+    last_jitter: Option<u32>,
+    max_jitter: Option<u32>,
+    /// RFC 3550 interarrival jitter estimate in microseconds
+    jint: f64,
+    /// Most recent probe outcomes (None for lost) when statistics cover a rolling window
+    window: VecDeque<Option<u32>>,
+    window_cap: usize,
+    /// Last few probe outcomes, oldest first, for trend displays
+    pub history: VecDeque<Outcome>,
+    history_cap: usize,
+    /// Ring buffer of the most recent reply RTTs, empty unless retention is enabled
+    samples: VecDeque<u32>,
+    sample_cap: usize,
+    /// Reply counts per whole millisecond of RTT over the run, empty unless bucketing is enabled
+    buckets: Vec<u64>,
+    bucketing: bool,
+    /// Primary responder also answers at another TTL, which points to a routing loop
+    pub in_loop: bool,
+    /// The last ROUTE_CHANGE_HISTORY switches of primary responder, oldest first
+    pub route_changes: VecDeque<RouteChange>,
+    /// Every switch of primary responder over the run, including those no longer kept
+    pub route_change_count: u32,
+    /// The primary responder switched during the latest cycle
+    pub route_changed: bool,
+}
+
+/// Route changes kept per hop; older ones only survive in route_change_count
+pub const ROUTE_CHANGE_HISTORY: usize = 16;
+
+/// One probe's outcome as kept in HopStats::history
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Reply(u32),
+    Lost,
+    /// An ICMP error such as unreachable, with its RTT when ErrorPolicy::Responded counted it
+    Error(Option<u32>),
+}
+
+impl Outcome {
+    /// RTT in microseconds if the probe counted as answered
+    pub fn rtt(self) -> Option<u32> {
+        match self {
+            Outcome::Reply(rtt) => Some(rtt),
+            Outcome::Error(rtt) => rtt,
+            Outcome::Lost => None,
+        }
+    }
+}
+
+/// A hop's primary (most frequent) responder switching from one address to another
+#[derive(Clone, Debug)]
+pub struct RouteChange {
+    pub at: SystemTime,
+    pub from: IpAddr,
+    pub to: IpAddr,
+}
+
+impl HopStats {
+    pub fn new(ttl: u8) -> Self {
+        Self {
+            ttl,
+            ip: None,
+            hostname: None,
+            asn: None,
+            location: None,
+            label: None,
+            ips: Vec::new(),
+            sent: 0,
+            received: 0,
+            last_rtt: None,
+            replied: false,
+            min_rtt: None,
+            max_rtt: None,
+            mean_rtt: 0.0,
+            m2_rtt: 0.0,
+            sum_jitter: 0,
+            prev_reply: None,
+            last_jitter: None,
+            max_jitter: None,
+            jint: 0.0,
+            window: VecDeque::new(),
+            window_cap: 0,
+            history: VecDeque::new(),
+            history_cap: 0,
+            samples: VecDeque::new(),
+            sample_cap: 0,
+            buckets: Vec::new(),
+            bucketing: false,
+            in_loop: false,
+            route_changes: VecDeque::new(),
+            route_change_count: 0,
+            route_changed: false,
+        }
+    }
+
+    /// Keep the last `cap` reply RTTs for percentile calculations
+    pub fn with_samples(mut self, cap: usize) -> Self {
+        self.sample_cap = cap;
+        self
+    }
+
+    /// Count replies into 1 ms RTT buckets for histograms
+    pub fn with_buckets(mut self, enabled: bool) -> Self {
+        self.bucketing = enabled;
+        self
+    }
+
+    /// Compute sent/loss/RTT statistics over only the last `cap` probes instead of the whole run
+    pub fn with_window(mut self, cap: usize) -> Self {
+        self.window_cap = cap;
+        self
+    }
+
+    /// Keep the last `cap` probe outcomes in `history`
+    pub fn with_history(mut self, cap: usize) -> Self {
+        self.history_cap = cap;
+        self
+    }
+
+    /// Count an answer from `ip`; a change of primary (most frequent) responder is recorded as a
+    /// route change and drops the stale hostname, while load balancing between known responders is not
+    pub fn observe_ip(&mut self, ip: IpAddr) {
+        match self.ips.iter_mut().find(|(seen, _)| *seen == ip) {
+            Some((_, count)) => *count += 1,
+            None => self.ips.push((ip, 1)),
+        }
+        // max_by_key keeps the last maximum, so reverse to let the first-seen address win ties
+        let primary = self.ips.iter().rev().max_by_key(|(_, count)| *count).map(|(ip, _)| *ip);
+        if primary != self.ip {
+            if let (Some(from), Some(to)) = (self.ip, primary) {
+                if self.route_changes.len() == ROUTE_CHANGE_HISTORY { self.route_changes.pop_front(); }
+                self.route_changes.push_back(RouteChange { at: SystemTime::now(), from, to });
+                self.route_change_count += 1;
+                self.route_changed = true;
+            }
+            self.ip = primary;
+            self.hostname = None;
+            self.asn = None;
+            self.location = None;
+            self.label = None;
+        }
+    }
+
+    pub fn record_response(&mut self, ip: IpAddr, rtt: u32) {
+        self.reply(ip, rtt);
+        self.remember(Outcome::Reply(rtt));
+    }
+
+    pub fn record_timeout(&mut self) {
+        self.lose();
+        self.remember(Outcome::Lost);
+    }
+
+    /// An ICMP error from `ip`: a reply under ErrorPolicy::Responded, else a loss that still names the hop
+    pub fn record_error(&mut self, ip: IpAddr, rtt: u32, policy: ErrorPolicy) {
+        match policy {
+            ErrorPolicy::Responded => {
+                self.reply(ip, rtt);
+                self.remember(Outcome::Error(Some(rtt)));
+            }
+            ErrorPolicy::Lost => {
+                self.observe_ip(ip);
+                self.lose();
+                self.remember(Outcome::Error(None));
+            }
+        }
+    }
+
+    fn reply(&mut self, ip: IpAddr, rtt: u32) {
+        self.observe_ip(ip);
+        self.accumulate(Some(rtt));
+        self.last_rtt = Some(rtt);
+        self.replied = true;
+        if self.sample_cap > 0 {
+            if self.samples.len() == self.sample_cap { self.samples.pop_front(); }
+            self.samples.push_back(rtt);
+        }
+        if self.bucketing {
+            let bucket = (rtt / 1000) as usize;
+            if bucket >= self.buckets.len() { self.buckets.resize(bucket + 1, 0); }
+            self.buckets[bucket] += 1;
+        }
+    }
+
+    fn lose(&mut self) {
+        self.accumulate(None);
+        self.replied = false;
+    }
+
+    fn remember(&mut self, outcome: Outcome) {
+        if self.history_cap == 0 { return; }
+        if self.history.len() == self.history_cap { self.history.pop_front(); }
+        self.history.push_back(outcome);
+    }
+
+    /// Fold one probe outcome into the statistics; with a window, rebuild them once the oldest drops out
+    fn accumulate(&mut self, rtt: Option<u32>) {
+        self.add(rtt);
+        if self.window_cap == 0 { return; }
+        self.window.push_back(rtt);
+        if self.window.len() > self.window_cap {
+            self.window.pop_front();
+            let window = std::mem::take(&mut self.window);
+            self.sent = 0;
+            self.received = 0;
+            self.mean_rtt = 0.0;
+            self.m2_rtt = 0.0;
+            self.min_rtt = None;
+            self.max_rtt = None;
+            self.sum_jitter = 0;
+            self.prev_reply = None;
+            self.last_jitter = None;
+            self.max_jitter = None;
+            self.jint = 0.0;
+            for &rtt in &window { self.add(rtt); }
+            self.window = window;
+        }
+    }
+
+    fn add(&mut self, rtt: Option<u32>) {
+        self.sent += 1;
+        let Some(rtt) = rtt else { return };
+        self.received += 1;
+        if let Some(prev) = self.prev_reply {
+            let delta = prev.abs_diff(rtt);
+            self.sum_jitter += delta as u64;
+            self.last_jitter = Some(delta);
+            self.max_jitter = Some(self.max_jitter.map_or(delta, |m| m.max(delta)));
+            self.jint += (delta as f64 - self.jint) / 16.0;
+        }
+        self.prev_reply = Some(rtt);
+        let delta = rtt as f64 - self.mean_rtt;
+        self.mean_rtt += delta / self.received as f64;
+        self.m2_rtt += delta * (rtt as f64 - self.mean_rtt);
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |m| m.min(rtt)));
+        self.max_rtt = Some(self.max_rtt.map_or(rtt, |m| m.max(rtt)));
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent == 0 { 0.0 } else { ((self.sent - self.received) as f64 / self.sent as f64) * 100.0 }
+    }
+
+    /// Mean RTT in milliseconds
+    pub fn avg_rtt(&self) -> f64 {
+        if self.received == 0 { 0.0 } else { self.mean_rtt / 1000.0 }
+    }
+
+    /// Sample standard deviation (n - 1) of the RTT in milliseconds
+    pub fn std_dev(&self) -> f64 {
+        if self.received < 2 { 0.0 } else {
+            let variance = self.m2_rtt / (self.received - 1) as f64;
+            if variance > 0.0 { variance.sqrt() / 1000.0 } else { 0.0 }
+        }
+    }
+
+    /// Mean absolute difference between consecutive reply RTTs in milliseconds
+    pub fn jitter(&self) -> f64 {
+        if self.received < 2 { 0.0 } else { self.sum_jitter as f64 / (self.received - 1) as f64 / 1000.0 }
+    }
+
+    /// Difference between the last two reply RTTs in milliseconds
+    pub fn last_jitter(&self) -> Option<f64> {
+        self.last_jitter.map(us_to_ms)
+    }
+
+    /// Largest difference between consecutive reply RTTs in milliseconds
+    pub fn max_jitter(&self) -> Option<f64> {
+        self.max_jitter.map(us_to_ms)
+    }
+
+    /// RFC 3550 interarrival jitter in milliseconds: each delta moves the estimate 1/16 of the way
+    pub fn interarrival_jitter(&self) -> f64 {
+        self.jint / 1000.0
+    }
+
+    /// Reply counts indexed by RTT in whole milliseconds; empty unless built with_buckets
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Nearest-rank percentile of the retained RTT samples in milliseconds
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        if self.samples.is_empty() { return None; }
+        let mut sorted: Vec<u32> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+        Some(us_to_ms(sorted[rank.clamp(1, sorted.len()) - 1]))
+    }
+
+    /// Half-width of the 95% confidence interval for the mean RTT
+    pub fn ci95(&self) -> f64 {
+        if self.received < 2 { 0.0 } else { 1.96 * self.std_dev() / (self.received as f64).sqrt() }
+    }
+}
+
+/// Flag hops whose primary responder shows up at more than one TTL.
+/// The target is skipped, as it may answer several probes with errors before it is reached.
+pub fn mark_loops(hops: &mut [HopStats], target_ip: IpAddr) {
+    let mut seen: HashMap<IpAddr, usize> = HashMap::new();
+    for ip in hops.iter().filter_map(|h| h.ip).filter(|&ip| ip != target_ip) {
+        *seen.entry(ip).or_insert(0) += 1;
+    }
+    for hop in hops.iter_mut() {
+        hop.in_loop = hop.ip.is_some_and(|ip| seen.get(&ip).is_some_and(|&n| n > 1));
+    }
+}
+
+/// Convert a stored microsecond RTT to milliseconds for display
+pub fn us_to_ms(us: u32) -> f64 {
+    us as f64 / 1000.0
+}
+
+/// Outcome of one probe; RTTs are in microseconds
+#[derive(Clone)]
+pub enum ProbeResult {
+    Reply { ip: IpAddr, rtt: u32 },
+    TtlExpired { ip: IpAddr, rtt: u32 },
+    Unreachable { ip: IpAddr, rtt: u32 },
+    Timeout,
+}
+
+/// Run `f` on its own thread and give up on it after `timeout`; None waits indefinitely.
+/// The system resolver cannot be cancelled, so an abandoned lookup finishes in the background.
+pub fn run_with_timeout<T: Send + 'static>(timeout: Option<Duration>, f: impl FnOnce() -> T + Send + 'static) -> Option<T> {
+    let Some(timeout) = timeout else { return Some(f()) };
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || { let _ = tx.send(f()); });
+    rx.recv_timeout(timeout).ok()
+}
+
+/// Forward lookup of a hostname, bounded by `timeout`
+fn lookup_addresses(target: &str, timeout: Option<Duration>) -> Result<Vec<IpAddr>, String> {
+    let host = target.to_string();
+    match run_with_timeout(timeout, move || lookup_host(&host)) {
+        Some(Ok(ips)) => Ok(ips),
+        Some(Err(e)) => Err(format!("Failed to resolve {}: {}", target, e)),
+        None => Err(format!("Timed out resolving {}", target)),
+    }
+}
+
+/// Resolve the target, preferring IPv4 unless the family is restricted
+pub fn resolve_target(target: &str, family: Family, timeout: Option<Duration>) -> Result<IpAddr, String> {
+    if let Ok(ip) = target.parse::<IpAddr>() {
+        if family.allows(ip) { return Ok(ip); }
+        return Err(format!("{} is not an {} address", target, family.name()));
+    }
+    let ips = lookup_addresses(target, timeout)?;
+    let preferred = if family == Family::Any { Family::V4 } else { family };
+    ips.iter().find(|ip| preferred.allows(**ip)).or_else(|| ips.iter().find(|ip| family.allows(**ip))).copied()
+        .ok_or_else(|| format!("No {} address found for {}", family.name(), target))
+}
+
+/// Every address of the target allowed by the family, deduplicated, IPv4 before IPv6
+pub fn resolve_all(target: &str, family: Family, timeout: Option<Duration>) -> Result<Vec<IpAddr>, String> {
+    if target.parse::<IpAddr>().is_ok() { return resolve_target(target, family, timeout).map(|ip| vec![ip]); }
+    let ips = lookup_addresses(target, timeout)?;
+    let mut all: Vec<IpAddr> = Vec::new();
+    for ip in ips.iter().filter(|ip| ip.is_ipv4()).chain(ips.iter().filter(|ip| ip.is_ipv6())) {
+        if family.allows(*ip) && !all.contains(ip) { all.push(*ip); }
+    }
+    if all.is_empty() { return Err(format!("No {} address found for {}", family.name(), target)); }
+    Ok(all)
+}
+
+pub fn reverse_lookup(ip: IpAddr) -> Option<String> {
+    if let Ok(hosts) = dns_lookup::lookup_addr(&ip) {
+        if !hosts.is_empty() && hosts != ip.to_string() { return Some(hosts); }
+    }
+    None
+}
+
+/// Origin AS of `ip` from Team Cymru's DNS service, formatted as "AS<number>"
+pub fn asn_lookup(ip: IpAddr) -> Option<String> {
+    static RESOLVER: OnceLock<Option<Resolver>> = OnceLock::new();
+    let resolver = RESOLVER.get_or_init(|| Resolver::from_system_conf().ok()).as_ref()?;
+    let name = match ip {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            format!("{}.{}.{}.{}.origin.asn.cymru.com.", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(v6) => {
+            let nibbles: String = v6.octets().iter().rev().map(|b| format!("{:x}.{:x}.", b & 0xf, b >> 4)).collect();
+            format!("{}origin6.asn.cymru.com.", nibbles)
+        }
+    };
+    // Answers look like "15169 | 8.8.8.0/24 | US | arin | 2023-12-28"; multi-origin prefixes list several ASNs
+    let txt = resolver.txt_lookup(name).ok()?.iter().next()?.to_string();
+    let asn = txt.split('|').next()?.split_whitespace().next()?;
+    asn.parse::<u32>().ok().map(|asn| format!("AS{}", asn))
+}
+
+/// Number of background threads serving reverse DNS lookups
+const DNS_WORKERS: usize = 4;
+
+/// Hop table shared between the probe loop and the reverse DNS workers
+pub type SharedHops = Arc<Mutex<Vec<HopStats>>>;
+
+/// Hop field a DnsResolver fills in
+type HopField = fn(&mut HopStats) -> &mut Option<String>;
+
+/// Answer for one address, shared by the worker threads
+type Lookup = Arc<dyn Fn(IpAddr) -> Option<String> + Send + Sync>;
+
+/// Background lookups (PTR names, ASNs or locations) so slow queries never stall the probe loop
+pub struct DnsResolver {
+    jobs: mpsc::Sender<(SharedHops, usize, IpAddr)>,
+    /// Lookups by address; None while pending or when there is no answer
+    cache: Arc<Mutex<HashMap<IpAddr, Option<String>>>>,
+    pending: Arc<AtomicUsize>,
+    field: HopField,
+}
+
+/// How long the final report waits for reverse lookups still in flight
+pub const DNS_DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
+
+impl DnsResolver {
+    /// Reverse DNS into each hop's hostname; a lookup outlasting `timeout` leaves the hop unnamed
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self::with_lookup(reverse_lookup, |hop| &mut hop.hostname, timeout)
+    }
+
+    /// Team Cymru origin AS into each hop's asn
+    pub fn asn() -> Self {
+        Self::with_lookup(asn_lookup, |hop| &mut hop.asn, None)
+    }
+
+    /// Caller-supplied names, such as an annotations file, into each hop's label
+    pub fn label(lookup: impl Fn(IpAddr) -> Option<String> + Send + Sync + 'static) -> Self {
+        Self::with_lookup(lookup, |hop| &mut hop.label, None)
+    }
+
+    /// Caller-supplied geolocation, such as a GeoIP database, into each hop's location
+    pub fn location(lookup: impl Fn(IpAddr) -> Option<String> + Send + Sync + 'static) -> Self {
+        Self::with_lookup(lookup, |hop| &mut hop.location, None)
+    }
+
+    /// Start the workers; answers are written straight into `field` of the requesting hop table
+    fn with_lookup(lookup: impl Fn(IpAddr) -> Option<String> + Send + Sync + 'static, field: HopField, timeout: Option<Duration>) -> Self {
+        let lookup: Lookup = Arc::new(lookup);
+        let (jobs, rx) = mpsc::channel::<(SharedHops, usize, IpAddr)>();
+        let rx = Arc::new(Mutex::new(rx));
+        let cache = Arc::new(Mutex::new(HashMap::new()));
+        let pending = Arc::new(AtomicUsize::new(0));
+        for _ in 0..DNS_WORKERS {
+            let (rx, cache, pending, lookup) = (rx.clone(), cache.clone(), pending.clone(), lookup.clone());
+            thread::spawn(move || loop {
+                let job = rx.lock().unwrap().recv();
+                let Ok((hops, hop_idx, ip)) = job else { break };
+                let lookup = lookup.clone();
+                let name = run_with_timeout(timeout, move || lookup(ip)).flatten();
+                cache.lock().unwrap().insert(ip, name.clone());
+                let mut hops = hops.lock().unwrap();
+                if name.is_some() && hops[hop_idx].ip == Some(ip) { *field(&mut hops[hop_idx]) = name; }
+                pending.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+        Self { jobs, cache, pending, field }
+    }
+
+    /// Seed the cache with names from an earlier run so those addresses are never looked up
+    pub fn preload(&self, names: impl IntoIterator<Item = (IpAddr, String)>) {
+        let mut cache = self.cache.lock().unwrap();
+        for (ip, name) in names { cache.insert(ip, Some(name)); }
+    }
+
+    /// Every address that resolved to a name so far
+    pub fn names(&self) -> Vec<(IpAddr, String)> {
+        let cache = self.cache.lock().unwrap();
+        cache.iter().filter_map(|(ip, name)| name.clone().map(|name| (*ip, name))).collect()
+    }
+
+    /// Block until queued lookups have finished or `timeout` elapses
+    pub fn drain(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while self.pending.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Fill the hop's field from the cache, queuing a lookup the first time its address is seen
+    pub fn resolve(&self, hops: &SharedHops, hop_idx: usize, hop: &mut HopStats) {
+        let Some(ip) = hop.ip else { return };
+        if (self.field)(hop).is_some() { return; }
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(&ip) {
+            Some(name) => *(self.field)(hop) = name.clone(),
+            None => {
+                cache.insert(ip, None);
+                self.pending.fetch_add(1, Ordering::SeqCst);
+                let _ = self.jobs.send((hops.clone(), hop_idx, ip));
+            }
+        }
+    }
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+/// Probe a single hop on a pinger that is reused across probes, from `source` when given
+pub fn probe_hop(pinger: &mut Pinger, buffer: &mut Buffer, target: IpAddr, ttl: u8, timeout: u32, source: Option<IpAddr>) -> ProbeResult {
+    pinger.set_ttl(ttl);
+    pinger.set_timeout(timeout);
+    let start = Instant::now();
+    let result = match (source, target) {
+        (Some(IpAddr::V4(src)), IpAddr::V4(dst)) => pinger.send_from(IpPair::V4 { src, dst }, buffer),
+        (Some(IpAddr::V6(src)), IpAddr::V6(dst)) => pinger.send_from(IpPair::V6 { src, dst }, buffer),
+        _ => pinger.send(target, buffer),
+    };
+    classify(result, buffer, target, elapsed_us(start))
+}
+
+fn elapsed_us(start: Instant) -> u32 {
+    duration_us(start.elapsed())
+}
+
+fn duration_us(duration: Duration) -> u32 {
+    duration.as_micros().min(u32::MAX as u128) as u32
+}
+
+/// Turn a send's outcome into a ProbeResult; errors name the responding router from the reply buffer
+fn classify(result: Result<u32, PingError>, buffer: &Buffer, target: IpAddr, elapsed: u32) -> ProbeResult {
+    match result {
+        Ok(_) => ProbeResult::Reply { ip: target, rtt: elapsed },
+        Err(PingError::TtlExpired) => {
+            if let Some(ip) = buffer.responding_ip() {
+                ProbeResult::TtlExpired { ip, rtt: elapsed }
+            } else {
+                ProbeResult::Timeout
+            }
+        }
+        Err(PingError::Timeout) => ProbeResult::Timeout,
+        Err(PingError::HostUnreachable) | Err(PingError::NetUnreachable)
+        | Err(PingError::ProtocolUnreachable) | Err(PingError::NeedsFragmented) => {
+            if let Some(ip) = buffer.responding_ip() {
+                ProbeResult::Unreachable { ip, rtt: elapsed }
+            } else {
+                ProbeResult::Timeout
+            }
+        }
+        Err(_) => ProbeResult::Timeout,
+    }
+}
+
+/// Whether ICMP can be sent at all; a Pinger missing only one family still works for the other
+pub fn check_pinger() -> Result<(), CreateError> {
+    match Pinger::new() {
+        Err(CreateError::None) => Err(CreateError::None),
+        _ => Ok(()),
+    }
+}
+
+/// Create a pinger, accepting one that only has a v4 or only a v6 handle
+pub fn create_pinger() -> Option<Pinger> {
+    match Pinger::new() {
+        Ok(p) | Err(CreateError::NoV4(p)) | Err(CreateError::NoV6(p)) => Some(p),
+        Err(CreateError::None) => None,
+    }
+}
+
+/// Upper bound on probe worker threads, however many targets and hops are traced
+pub const MAX_PROBE_WORKERS: usize = 64;
+
+/// SplitMix64, a small seedable generator for probe timing and sizes
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// Seeded from the system clock, for runs that need not be reproducible
+    pub fn from_clock() -> Self {
+        Self(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in [-1, 1)
+    pub fn next_unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+    }
+}
+
+/// IP and ICMP header bytes of an echo request, the size of a probe with no payload
+pub fn header_len(target: IpAddr) -> usize {
+    if target.is_ipv4() { 28 } else { 48 }
+}
+
+/// Payload bytes for a probe of `packet_size` bytes in all, counted like mtr's -s;
+/// a negative size picks a random total up to its absolute value for each probe
+pub fn payload_len(target: IpAddr, packet_size: i32, rng: &mut Rng) -> usize {
+    let header = header_len(target);
+    let total = packet_size.unsigned_abs() as usize;
+    let total = if packet_size < 0 && total > header { header + (rng.next_u64() % (total - header + 1) as u64) as usize } else { total };
+    total.saturating_sub(header)
+}
+
+/// Request data for one probe: `payload_len` bytes of `bitpattern`, or of a random byte when it is negative
+pub fn payload(target: IpAddr, packet_size: i32, bitpattern: i16, rng: &mut Rng) -> Vec<u8> {
+    let len = payload_len(target, packet_size, rng);
+    let fill = u8::try_from(bitpattern).unwrap_or_else(|_| rng.next_u64() as u8);
+    vec![fill; len]
+}
+
+/// Job index, target, TTL, source address and payload of one probe sent to a worker
+type ProbeJob = (usize, IpAddr, u8, Option<IpAddr>, Vec<u8>);
+
+/// How a Prober gets probes onto the wire
+enum Engine {
+    /// Worker threads pulling from one job queue, each blocking in IcmpSendEcho on its own Pinger
+    Pool { jobs: mpsc::Sender<ProbeJob>, results: mpsc::Receiver<(usize, ProbeResult)> },
+    /// Overlapped IcmpSendEcho2 requests, all completed on winping's one I/O thread
+    Overlapped(AsyncPinger),
+}
+
+/// Long-lived probe machinery: a pool of workers each owning one Pinger, or overlapped requests
+pub struct Prober {
+    engine: Engine,
+    retries: u32,
+    source: Option<IpAddr>,
+    packet_size: i32,
+    bitpattern: i16,
+    rng: Mutex<Rng>,
+}
+
+impl Prober {
+    /// Start a pool of `workers` threads, each opening its ICMP handle once up front;
+    /// fails if any of them cannot, rather than letting its probes look like timeouts
+    pub fn new(workers: usize, timeout: u32) -> Result<Self, CreateError> {
+        let (jobs, rx) = mpsc::channel::<ProbeJob>();
+        let rx = Arc::new(Mutex::new(rx));
+        let (result_tx, results) = mpsc::channel();
+        let (ready_tx, ready) = mpsc::channel();
+        for _ in 0..workers {
+            let (rx, result_tx, ready_tx) = (rx.clone(), result_tx.clone(), ready_tx.clone());
+            thread::spawn(move || {
+                let Some(mut pinger) = create_pinger() else {
+                    let _ = ready_tx.send(false);
+                    return;
+                };
+                let _ = ready_tx.send(true);
+                let mut buffer = Buffer::new();
+                // A free worker takes the next job, so one slow hop never holds up the others queued behind it
+                loop {
+                    let job = rx.lock().unwrap().recv();
+                    let Ok((idx, target, ttl, source, payload)) = job else { break };
+                    buffer.request_data = payload;
+                    let result = probe_hop(&mut pinger, &mut buffer, target, ttl, timeout, source);
+                    if result_tx.send((idx, result)).is_err() { break; }
+                }
+            });
+        }
+        // Workers that did open a handle exit once the job queue is dropped with the error
+        if !ready.iter().take(workers).all(|opened| opened) { return Err(CreateError::None); }
+        Ok(Self::with_engine(Engine::Pool { jobs, results }))
+    }
+
+    /// Send every probe of a cycle at once as an overlapped request instead of tying up a thread per probe
+    pub fn overlapped(timeout: u32) -> Self {
+        let mut pinger = AsyncPinger::new();
+        pinger.set_timeout(timeout);
+        Self::with_engine(Engine::Overlapped(pinger))
+    }
+
+    fn with_engine(engine: Engine) -> Self {
+        Self { engine, retries: 0, source: None, packet_size: 0, bitpattern: 0, rng: Mutex::new(Rng::from_clock()) }
+    }
+
+    /// Re-probe a timed-out job up to `retries` more times before it counts as lost
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Send every probe from this local address instead of the one the routing table picks
+    pub fn with_source(mut self, source: Option<IpAddr>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Size every probe as `packet_size` bytes in all (see [`payload_len`]); 0 sends no payload
+    pub fn with_packet_size(mut self, packet_size: i32) -> Self {
+        self.packet_size = packet_size;
+        self
+    }
+
+    /// Fill every payload with this byte, or a random one per probe when negative
+    pub fn with_bitpattern(mut self, bitpattern: i16) -> Self {
+        self.bitpattern = bitpattern;
+        self
+    }
+
+    /// Probe every job, queued for the workers or all in flight at once, and return
+    /// the results in job order, keeping the first answer each job got across its retries;
+    /// fails when the overlapped engine itself breaks rather than reporting total loss
+    pub fn run(&self, jobs: &[(IpAddr, u8)]) -> Result<Vec<ProbeResult>, String> {
+        let mut results = vec![ProbeResult::Timeout; jobs.len()];
+        let mut pending: Vec<usize> = (0..jobs.len()).collect();
+        let mut rng = self.rng.lock().unwrap();
+        for _ in 0..=self.retries {
+            let batch: Vec<ProbeJob> = pending.iter().map(|&idx| {
+                let (target, ttl) = jobs[idx];
+                (idx, target, ttl, self.source, payload(target, self.packet_size, self.bitpattern, &mut rng))
+            }).collect();
+            let answers = match &self.engine {
+                Engine::Pool { jobs, results: replies } => {
+                    for job in batch { jobs.send(job).expect("probe workers exited"); }
+                    replies.iter().take(pending.len()).collect()
+                }
+                Engine::Overlapped(pinger) => probe_overlapped(pinger, batch)?,
+            };
+            for (idx, result) in answers { results[idx] = result; }
+            pending.retain(|&idx| matches!(results[idx], ProbeResult::Timeout));
+            if pending.is_empty() { break; }
+        }
+        Ok(results)
+    }
+}
+
+/// Wakes the thread waiting in probe_overlapped when its request completes, noting when that was
+struct Completion {
+    waiter: thread::Thread,
+    at: OnceLock<Instant>,
+}
+
+impl Wake for Completion {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.at.get_or_init(Instant::now);
+        self.waiter.unpark();
+    }
+}
+
+/// Job index, target, send time, pending reply and completion of one overlapped request
+type Request = (usize, IpAddr, Instant, PingFuture, Arc<Completion>);
+
+/// Codes from IP_STATUS_BASE to MAX_IP_STATUS describe one probe's fate; any other
+/// code is a Windows error from the ICMP API itself
+const IP_STATUS: std::ops::RangeInclusive<u32> = 11000..=11050;
+
+/// How long past the probe timeout to wait for winping's I/O thread before giving up on it
+const OVERLAPPED_GRACE: Duration = Duration::from_secs(1);
+
+/// Start every job as an overlapped request, then park until all have completed. Each RTT runs
+/// from its own send to the wake-up of its own request, so later sends and polling do not add to it.
+fn probe_overlapped(pinger: &AsyncPinger, batch: Vec<ProbeJob>) -> Result<Vec<(usize, ProbeResult)>, String> {
+    let mut done = Vec::with_capacity(batch.len());
+    let mut in_flight: Vec<Option<Request>> = Vec::with_capacity(batch.len());
+    for (idx, target, ttl, source, payload) in batch {
+        let mut pinger = pinger.clone();
+        pinger.set_ttl(ttl);
+        let mut buffer = Buffer::new();
+        buffer.request_data = payload;
+        let completion = Arc::new(Completion { waiter: thread::current(), at: OnceLock::new() });
+        let start = Instant::now();
+        let future = match (source, target) {
+            (Some(IpAddr::V4(src)), IpAddr::V4(dst)) => pinger.send_from(IpPair::V4 { src, dst }, buffer),
+            (Some(IpAddr::V6(src)), IpAddr::V6(dst)) => pinger.send_from(IpPair::V6 { src, dst }, buffer),
+            _ => pinger.send(target, buffer),
+        };
+        // Polled at once so its waker is registered before the reply can arrive
+        let mut request = (idx, target, start, future, completion);
+        match poll_request(&mut request)? {
+            Poll::Ready(result) => done.push((idx, result)),
+            Poll::Pending => in_flight.push(Some(request)),
+        }
+    }
+    let deadline = Instant::now() + Duration::from_millis(pinger.timeout().into()) + OVERLAPPED_GRACE;
+    while in_flight.iter().any(Option::is_some) {
+        // A completion between the poll and here leaves the unpark token set, so this returns at once
+        thread::park_timeout(deadline.saturating_duration_since(Instant::now()));
+        for slot in &mut in_flight {
+            let Some(request) = slot else { continue };
+            if let Poll::Ready(result) = poll_request(request)? {
+                done.push((request.0, result));
+                *slot = None;
+            }
+        }
+        if Instant::now() >= deadline && in_flight.iter().any(Option::is_some) {
+            return Err(format!("overlapped ICMP requests still pending {} ms after their timeout", OVERLAPPED_GRACE.as_millis()));
+        }
+    }
+    Ok(done)
+}
+
+/// Poll one overlapped request, timing its reply from the moment its waker fired
+fn poll_request((_, target, start, future, completion): &mut Request) -> Result<Poll<ProbeResult>, String> {
+    let waker = Waker::from(completion.clone());
+    let Poll::Ready(AsyncResult { result, buffer }) = Pin::new(future).poll(&mut Context::from_waker(&waker)) else { return Ok(Poll::Pending) };
+    if let Err(PingError::Other(code)) = result {
+        if !IP_STATUS.contains(&code) { return Err(format!("overlapped ICMP request failed: {}", PingError::Other(code))); }
+    }
+    // A request that completed before its first poll never woke anyone, so it finishes now
+    let finished = *completion.at.get_or_init(Instant::now);
+    Ok(Poll::Ready(classify(result, &buffer, *target, duration_us(finished.saturating_duration_since(*start)))))
+}
+
+/// A set of TTLs, for probing only part of a path
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TtlSet([u128; 2]);
+
+impl TtlSet {
+    pub fn insert(&mut self, ttl: u8) {
+        self.0[usize::from(ttl >> 7)] |= 1 << (ttl & 127);
+    }
+
+    pub fn contains(&self, ttl: u8) -> bool {
+        self.0[usize::from(ttl >> 7)] & (1 << (ttl & 127)) != 0
+    }
+
+    pub fn min(&self) -> Option<u8> {
+        (0..=u8::MAX).find(|&ttl| self.contains(ttl))
+    }
+
+    pub fn max(&self) -> Option<u8> {
+        (0..=u8::MAX).rev().find(|&ttl| self.contains(ttl))
+    }
+}
+
+/// Probe settings shared by every traced destination
+#[derive(Clone, Copy, Debug)]
+pub struct TraceConfig {
+    pub first_ttl: u8,
+    pub max_ttl: u8,
+    pub error_policy: ErrorPolicy,
+    /// Reply RTTs kept per hop for percentiles; 0 keeps none
+    pub sample_cap: usize,
+    /// Probes per hop that statistics cover; 0 covers the whole run
+    pub window: usize,
+    /// Probe outcomes per hop kept in HopStats::history; 0 keeps none
+    pub history: usize,
+    /// Count replies per hop into RTT buckets for histograms
+    pub buckets: bool,
+    /// Unanswered hops past the furthest known one before probing stops; 0 never stops
+    pub max_unknown: u8,
+    /// Only probe and show these TTLs; None covers first_ttl through max_ttl
+    pub ttls: Option<TtlSet>,
+}
+
+/// Probe state for one traced destination
+pub struct Tracer {
+    pub target: String,
+    pub target_ip: IpAddr,
+    pub hops: SharedHops,
+    /// TTL at which the target itself first answered
+    pub target_ttl: Option<u8>,
+    /// Probing cap from max_unknown while the target has not answered
+    unknown_limit: Option<u8>,
+    config: TraceConfig,
+}
+
+impl Tracer {
+    pub fn new(target: &str, target_ip: IpAddr, config: TraceConfig) -> Self {
+        let hops = Arc::new(Mutex::new(Self::empty_hops(config)));
+        Self { target: target.to_string(), target_ip, hops, target_ttl: None, unknown_limit: None, config }
+    }
+
+    fn empty_hops(config: TraceConfig) -> Vec<HopStats> {
+        (1..=config.max_ttl)
+            .map(|ttl| HopStats::new(ttl).with_samples(config.sample_cap).with_window(config.window).with_history(config.history).with_buckets(config.buckets))
+            .collect()
+    }
+
+    /// Forget every statistic and the discovered path, as if the trace had just started
+    pub fn reset(&mut self) {
+        *self.hops.lock().unwrap() = Self::empty_hops(self.config);
+        self.target_ttl = None;
+        self.unknown_limit = None;
+    }
+
+    /// Last TTL worth probing and showing: the target's TTL once it has answered,
+    /// otherwise max_unknown hops past the furthest one that has
+    pub fn last_ttl(&self) -> u8 {
+        self.target_ttl.or(self.unknown_limit).unwrap_or(self.config.max_ttl)
+    }
+
+    /// TTLs probed this cycle: first_ttl through last_ttl, narrowed to TraceConfig::ttls
+    fn probed_ttls(&self) -> impl Iterator<Item = u8> {
+        let ttls = self.config.ttls;
+        (self.config.first_ttl..=self.last_ttl()).filter(move |&ttl| ttls.is_none_or(|set| set.contains(ttl)))
+    }
+
+    /// Probe jobs for this cycle, one per probed TTL
+    pub fn jobs(&self) -> Vec<(IpAddr, u8)> {
+        self.probed_ttls().map(|ttl| (self.target_ip, ttl)).collect()
+    }
+
+    /// Fold the results for jobs() into the hop table, taking exactly that many from `results`
+    pub fn record(&mut self, results: &mut impl Iterator<Item = ProbeResult>, resolvers: &[DnsResolver]) {
+        let mut hops = self.hops.lock().unwrap();
+        let cycle: Vec<(u8, ProbeResult)> = self.probed_ttls().zip(results).collect();
+        // Probes past the path length also reach the target, so on the cycle that discovers it
+        // the replies beyond its TTL say nothing about those hops and are dropped
+        if self.target_ttl.is_none() {
+            self.target_ttl = cycle.iter().find(|(_, r)| matches!(r, ProbeResult::Reply { .. })).map(|&(ttl, _)| ttl);
+        }
+        let last_ttl = self.last_ttl();
+
+        for (ttl, result) in cycle.into_iter().filter(|&(ttl, _)| ttl <= last_ttl) {
+            let hop_idx = (ttl - 1) as usize;
+            hops[hop_idx].route_changed = false;
+            match result {
+                ProbeResult::Reply { ip, rtt } => {
+                    hops[hop_idx].record_response(ip, rtt);
+                }
+                ProbeResult::TtlExpired { ip, rtt } => {
+                    hops[hop_idx].record_response(ip, rtt);
+                }
+                ProbeResult::Unreachable { ip, rtt } => {
+                    hops[hop_idx].record_error(ip, rtt, self.config.error_policy);
+                }
+                ProbeResult::Timeout => {
+                    hops[hop_idx].record_timeout();
+                }
+            }
+        }
+        mark_loops(&mut hops[..last_ttl as usize], self.target_ip);
+        // An explicit TTL set already says how far to probe
+        if self.target_ttl.is_none() && self.config.max_unknown > 0 && self.config.ttls.is_none() {
+            let known = hops.iter().rposition(|h| h.ip.is_some()).map_or(self.config.first_ttl - 1, |idx| idx as u8 + 1);
+            self.unknown_limit = Some(known.saturating_add(self.config.max_unknown).min(self.config.max_ttl));
+        }
+        for dns in resolvers {
+            for (hop_idx, hop) in hops.iter_mut().enumerate() { dns.resolve(&self.hops, hop_idx, hop); }
+        }
+    }
+
+    /// Probe every hop once and return the hops now on display
+    pub fn run_cycle(&mut self, prober: &Prober, resolvers: &[DnsResolver]) -> Result<Vec<HopStats>, String> {
+        let results = prober.run(&self.jobs())?;
+        self.record(&mut results.into_iter(), resolvers);
+        Ok(self.visible_hops())
+    }
+
+    /// Copy of the hops probed each cycle
+    pub fn visible_hops(&self) -> Vec<HopStats> {
+        let hops = self.hops.lock().unwrap();
+        self.probed_ttls().map(|ttl| hops[(ttl - 1) as usize].clone()).collect()
+    }
+
+    /// The target has not answered yet the hop at max_ttl still does, so the path is longer than max_ttl
+    pub fn beyond_max_ttl(&self) -> bool {
+        if self.target_ttl.is_some() || self.probed_ttls().last() != Some(self.config.max_ttl) { return false; }
+        let hops = self.hops.lock().unwrap();
+        let last = &hops[(self.config.max_ttl - 1) as usize];
+        last.replied && last.ip != Some(self.target_ip)
+    }
+
+    /// Loss percent at the target's hop, once the target has answered
+    pub fn target_loss(&self) -> Option<f64> {
+        self.target_ttl.map(|ttl| self.hops.lock().unwrap()[(ttl - 1) as usize].loss_percent())
+    }
+
+    /// The target's RTT in microseconds if it answered in the most recent cycle
+    pub fn target_rtt(&self) -> Option<u32> {
+        let ttl = self.target_ttl?;
+        let hops = self.hops.lock().unwrap();
+        let hop = &hops[(ttl - 1) as usize];
+        if hop.replied { hop.last_rtt } else { None }
+    }
+}
+
+/// One probe of a cycle, as its (target, TTL) job and the result it got
+pub type SentProbe = ((IpAddr, u8), ProbeResult);
+
+/// Destinations probed together on one Prober, as the CLI does, for embedding without it
+pub struct MtrSession {
+    pub traces: Vec<Tracer>,
+    /// Lookups (reverse DNS, ASN, ...) that fill in hop names after every cycle
+    pub resolvers: Vec<DnsResolver>,
+    prober: Prober,
+    config: TraceConfig,
+}
+
+impl MtrSession {
+    /// A session with no destinations yet; add them with add_target
+    pub fn new(config: TraceConfig, prober: Prober) -> Self {
+        Self { traces: Vec::new(), prober, resolvers: Vec::new(), config }
+    }
+
+    /// Lookups (reverse DNS, ASN, ...) that fill in hop names after every cycle
+    pub fn with_resolvers(mut self, resolvers: Vec<DnsResolver>) -> Self {
+        self.resolvers = resolvers;
+        self
+    }
+
+    /// Resolve `target` and trace it from the next cycle on
+    pub fn add_target(&mut self, target: &str, family: Family, timeout: Option<Duration>) -> Result<IpAddr, String> {
+        let ip = resolve_target(target, family, timeout)?;
+        self.add_address(target, ip);
+        Ok(ip)
+    }
+
+    /// Trace `ip`, already resolved from `target`, from the next cycle on
+    pub fn add_address(&mut self, target: &str, ip: IpAddr) {
+        self.traces.push(Tracer::new(target, ip, self.config));
+    }
+
+    /// Probe every hop of every destination once, all in parallel on the shared Prober,
+    /// and return each probe sent with its result
+    pub fn run_cycle(&mut self) -> Result<Vec<SentProbe>, String> {
+        let jobs: Vec<(IpAddr, u8)> = self.traces.iter().flat_map(Tracer::jobs).collect();
+        let results = self.prober.run(&jobs)?;
+        let mut recorded = results.iter().cloned();
+        for trace in &mut self.traces { trace.record(&mut recorded, &self.resolvers); }
+        Ok(jobs.into_iter().zip(results).collect())
+    }
+
+    /// Forget every destination's statistics and discovered path
+    pub fn reset(&mut self) {
+        for trace in &mut self.traces { trace.reset(); }
+    }
+
+    /// The hops now on display for each destination, in the order they were added
+    pub fn snapshot(&self) -> Vec<Vec<HopStats>> {
+        self.traces.iter().map(Tracer::visible_hops).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const HOP_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    #[test]
+    fn std_dev_is_sample_standard_deviation() {
+        let mut hop = HopStats::new(1);
+        for ms in [10, 12, 14, 16, 18] { hop.record_response(HOP_IP, ms * 1000); }
+        // Squared deviations 16 + 4 + 0 + 4 + 16 = 40, divided by n - 1 = 4
+        assert!((hop.avg_rtt() - 14.0).abs() < 1e-9);
+        assert!((hop.std_dev() - 10f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn std_dev_is_stable_for_large_rtts_with_small_spread() {
+        let mut hop = HopStats::new(1);
+        for us in [4_000_000_000, 4_000_000_001, 4_000_000_002] { hop.record_response(HOP_IP, us); }
+        assert!((hop.std_dev() - 0.001).abs() < 1e-9);
+    }
+
+    #[test]
+    fn primary_ip_is_most_frequent_responder() {
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 1000);
+        hop.record_response(other, 1000);
+        assert_eq!(hop.ip, Some(HOP_IP), "first-seen address wins ties");
+        hop.record_response(other, 1000);
+        assert_eq!(hop.ip, Some(other));
+        assert_eq!(hop.ips, vec![(HOP_IP, 1), (other, 2)]);
+    }
+
+    #[test]
+    fn jitter_is_mean_absolute_delta_of_consecutive_replies() {
+        let mut hop = HopStats::new(1);
+        for ms in [10, 14, 11, 11] { hop.record_response(HOP_IP, ms * 1000); }
+        hop.record_timeout();
+        // |14 - 10| + |11 - 14| + |11 - 11| = 7 over 3 intervals
+        assert!((hop.jitter() - 7.0 / 3.0).abs() < 1e-9);
+        assert_eq!(hop.last_jitter(), Some(0.0));
+        assert_eq!(hop.max_jitter(), Some(4.0));
+        // 4/16 = 0.25, then 0.25 + (3 - 0.25)/16, then decays toward 0
+        let jint = 0.25 + 2.75 / 16.0;
+        assert!((hop.interarrival_jitter() - (jint - jint / 16.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn percentiles_use_nearest_rank_over_the_sample_window() {
+        let mut hop = HopStats::new(1).with_samples(10);
+        for ms in 1..=20 { hop.record_response(HOP_IP, ms * 1000); }
+        // Only the last 10 samples (11..=20 ms) are retained
+        assert_eq!(hop.percentile(50.0), Some(15.0));
+        assert_eq!(hop.percentile(90.0), Some(19.0));
+        assert_eq!(hop.percentile(99.0), Some(20.0));
+        assert_eq!(HopStats::new(1).percentile(50.0), None);
+    }
+
+    #[test]
+    fn repeated_responders_are_marked_as_a_loop() {
+        let router = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 9));
+        let target = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        let mut hops: Vec<HopStats> = (1..=6).map(HopStats::new).collect();
+        hops[0].record_response(HOP_IP, 1000);
+        hops[1].record_response(router, 1000);
+        hops[3].record_response(router, 1000);
+        hops[4].record_response(target, 1000);
+        hops[5].record_response(target, 1000);
+        mark_loops(&mut hops, target);
+        let looped: Vec<u8> = hops.iter().filter(|h| h.in_loop).map(|h| h.ttl).collect();
+        assert_eq!(looped, vec![2, 4]);
+    }
+
+    #[test]
+    fn replies_beyond_the_discovered_target_are_dropped() {
+        let target = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        let config = TraceConfig { first_ttl: 1, max_ttl: 5, error_policy: ErrorPolicy::Lost, sample_cap: 0, window: 0, history: 0, buckets: false, max_unknown: 0, ttls: None };
+        let mut tracer = Tracer::new("target", target, config);
+        let mut cycle = vec![
+            ProbeResult::TtlExpired { ip: HOP_IP, rtt: 1000 },
+            ProbeResult::Timeout,
+            ProbeResult::Reply { ip: target, rtt: 3000 },
+            ProbeResult::Reply { ip: target, rtt: 3000 },
+            ProbeResult::Reply { ip: target, rtt: 3000 },
+        ].into_iter();
+        tracer.record(&mut cycle, &[]);
+
+        assert_eq!(tracer.target_ttl, Some(3));
+        assert_eq!(tracer.jobs().len(), 3);
+        let visible: Vec<u8> = tracer.visible_hops().iter().map(|h| h.ttl).collect();
+        assert_eq!(visible, vec![1, 2, 3]);
+        let hops = tracer.hops.lock().unwrap();
+        assert!(hops[3..].iter().all(|h| h.sent == 0 && h.ip.is_none()));
+    }
+
+    #[test]
+    fn probing_stops_after_max_unknown_silent_hops() {
+        let target = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        let config = TraceConfig { first_ttl: 1, max_ttl: 10, error_policy: ErrorPolicy::Lost, sample_cap: 0, window: 0, history: 0, buckets: false, max_unknown: 3, ttls: None };
+        let mut tracer = Tracer::new("target", target, config);
+        let mut cycle = std::iter::once(ProbeResult::TtlExpired { ip: HOP_IP, rtt: 1000 })
+            .chain(std::iter::once(ProbeResult::Timeout).cycle().take(9));
+        tracer.record(&mut cycle, &[]);
+        assert_eq!(tracer.last_ttl(), 4);
+
+        // A hop answering inside the cap pushes it further out
+        let mut cycle = vec![
+            ProbeResult::TtlExpired { ip: HOP_IP, rtt: 1000 },
+            ProbeResult::Timeout,
+            ProbeResult::TtlExpired { ip: HOP_IP, rtt: 1000 },
+            ProbeResult::Timeout,
+        ].into_iter();
+        tracer.record(&mut cycle, &[]);
+        assert_eq!(tracer.jobs().len(), 6);
+    }
+
+    #[test]
+    fn ttl_set_limits_jobs_and_rows() {
+        let mut ttls = TtlSet::default();
+        for ttl in [2, 4, 5] { ttls.insert(ttl); }
+        let config = TraceConfig { first_ttl: 1, max_ttl: 6, error_policy: ErrorPolicy::Lost, sample_cap: 0, window: 0, history: 0, buckets: false, max_unknown: 5, ttls: Some(ttls) };
+        let mut tracer = Tracer::new("target", IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), config);
+        assert_eq!(tracer.jobs().iter().map(|&(_, ttl)| ttl).collect::<Vec<_>>(), vec![2, 4, 5]);
+        let mut cycle = vec![ProbeResult::Timeout, ProbeResult::TtlExpired { ip: HOP_IP, rtt: 1000 }, ProbeResult::Timeout].into_iter();
+        tracer.record(&mut cycle, &[]);
+        let hops = tracer.visible_hops();
+        assert_eq!(hops.iter().map(|h| h.ttl).collect::<Vec<_>>(), vec![2, 4, 5]);
+        assert_eq!(hops[1].ip, Some(HOP_IP));
+        assert_eq!(ttls.max(), Some(5));
+    }
+
+    #[test]
+    fn answering_last_hop_means_max_ttl_is_too_low() {
+        let target = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        let config = TraceConfig { first_ttl: 1, max_ttl: 2, error_policy: ErrorPolicy::Lost, sample_cap: 0, window: 0, history: 0, buckets: false, max_unknown: 0, ttls: None };
+        let mut tracer = Tracer::new("target", target, config);
+        tracer.record(&mut vec![ProbeResult::TtlExpired { ip: HOP_IP, rtt: 1000 }, ProbeResult::Timeout].into_iter(), &[]);
+        assert!(!tracer.beyond_max_ttl());
+        tracer.record(&mut vec![ProbeResult::Timeout, ProbeResult::TtlExpired { ip: HOP_IP, rtt: 1000 }].into_iter(), &[]);
+        assert!(tracer.beyond_max_ttl());
+    }
+
+    #[test]
+    fn window_statistics_forget_old_probes() {
+        let mut hop = HopStats::new(1).with_window(3);
+        hop.record_timeout();
+        hop.record_timeout();
+        for ms in [40, 10, 20, 30] { hop.record_response(HOP_IP, ms * 1000); }
+        // Only 10, 20 and 30 ms remain; the losses and the 40 ms reply have aged out
+        assert_eq!((hop.sent, hop.received), (3, 3));
+        assert_eq!(hop.loss_percent(), 0.0);
+        assert!((hop.avg_rtt() - 20.0).abs() < 1e-9);
+        assert_eq!((hop.min_rtt, hop.max_rtt), (Some(10_000), Some(30_000)));
+        assert!((hop.std_dev() - 10.0).abs() < 1e-9);
+        assert!((hop.jitter() - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn packet_size_counts_headers_and_negative_is_random() {
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        let mut rng = Rng::new(7);
+        assert_eq!(payload_len(HOP_IP, 0, &mut rng), 0);
+        assert_eq!(payload_len(HOP_IP, 64, &mut rng), 36);
+        assert_eq!(payload_len(v6, 64, &mut rng), 16);
+        assert_eq!(payload_len(HOP_IP, 20, &mut rng), 0);
+        let sizes: Vec<usize> = (0..200).map(|_| payload_len(HOP_IP, -100, &mut rng)).collect();
+        assert!(sizes.iter().all(|&len| len <= 72));
+        assert!(sizes.iter().any(|&len| len != sizes[0]));
+        assert_eq!(payload(HOP_IP, 32, 0xAA, &mut rng), vec![0xAA; 4]);
+    }
+
+    #[test]
+    fn run_with_timeout_abandons_slow_work() {
+        assert_eq!(run_with_timeout(None, || 7), Some(7));
+        assert_eq!(run_with_timeout(Some(Duration::from_secs(5)), || 7), Some(7));
+        let slow = run_with_timeout(Some(Duration::from_millis(20)), || thread::sleep(Duration::from_secs(2)));
+        assert_eq!(slow, None);
+    }
+
+    #[test]
+    fn primary_switch_is_recorded_as_a_route_change() {
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 1000);
+        assert!(!hop.route_changed && hop.route_changes.is_empty());
+        hop.record_response(other, 1000);
+        hop.record_response(other, 1000);
+        assert!(hop.route_changed);
+        assert_eq!(hop.route_changes.len(), 1);
+        assert_eq!((hop.route_changes[0].from, hop.route_changes[0].to), (HOP_IP, other));
+    }
+
+    #[test]
+    fn takeover_is_a_route_change_once_the_new_router_answers_most() {
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let mut hop = HopStats::new(1);
+        for _ in 0..20 { hop.record_response(HOP_IP, 1000); }
+        for _ in 0..20 { hop.record_response(other, 1000); }
+        assert!(hop.route_changes.is_empty());
+        hop.record_response(other, 1000);
+        assert!(hop.route_changed);
+        assert_eq!(hop.route_change_count, 1);
+        assert_eq!((hop.route_changes[0].from, hop.route_changes[0].to), (HOP_IP, other));
+        assert_eq!(hop.ip, Some(other));
+    }
+
+    #[test]
+    fn load_balancing_between_known_responders_is_not_a_route_change() {
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let mut hop = HopStats::new(1);
+        for _ in 0..1000 {
+            hop.record_response(HOP_IP, 1000);
+            hop.record_response(other, 1000);
+        }
+        assert_eq!(hop.route_change_count, 0);
+        assert!(hop.route_changes.is_empty());
+    }
+
+    #[test]
+    fn route_change_history_is_capped_but_counted() {
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 1000);
+        // Each side takes the lead by two answers in turn
+        for round in 0..40 {
+            let ip = if round % 2 == 0 { other } else { HOP_IP };
+            hop.record_response(ip, 1000);
+            hop.record_response(ip, 1000);
+        }
+        assert_eq!(hop.route_change_count, 40);
+        assert_eq!(hop.route_changes.len(), ROUTE_CHANGE_HISTORY);
+    }
+
+    const SESSION_CONFIG: TraceConfig = TraceConfig { first_ttl: 1, max_ttl: 4, error_policy: ErrorPolicy::Lost, sample_cap: 0, window: 0, history: 0, buckets: false, max_unknown: 0, ttls: None };
+
+    #[test]
+    fn session_snapshot_follows_the_order_targets_were_added() {
+        let mut session = MtrSession::new(SESSION_CONFIG, Prober::overlapped(1000));
+        let ip = session.add_target("192.0.2.7", Family::V4, None).unwrap();
+        session.add_address("second", HOP_IP);
+        assert!(session.add_target("192.0.2.8", Family::V6, None).is_err());
+        let snapshot = session.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!((session.traces[0].target_ip, session.traces[1].target.as_str()), (ip, "second"));
+        // Before the first cycle every TTL is on display, none of them probed yet
+        assert!(snapshot.iter().all(|hops| hops.len() == 4 && hops.iter().all(|hop| hop.sent == 0)));
+    }
+
+    #[test]
+    fn session_cycle_records_what_it_returns() {
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut session = MtrSession::new(SESSION_CONFIG, Prober::overlapped(1000));
+        session.add_address("localhost", loopback);
+        let probes = session.run_cycle().unwrap();
+        assert_eq!(probes.iter().map(|&((_, ttl), _)| ttl).collect::<Vec<_>>(), [1, 2, 3, 4]);
+        // Loopback answers at the first TTL, so the path is one hop long from then on
+        assert_eq!(session.traces[0].target_ttl, Some(1));
+        let hops = &session.snapshot()[0];
+        assert_eq!((hops.len(), hops[0].received, hops[0].ip), (1, 1, Some(loopback)));
+        session.reset();
+        assert_eq!(session.traces[0].target_ttl, None);
+        assert_eq!(session.snapshot()[0].len(), 4);
+    }
+
+    #[test]
+    fn std_dev_needs_two_replies() {
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 5000);
+        hop.record_timeout();
+        assert_eq!(hop.std_dev(), 0.0);
+    }
+}
@@ -1,12 +1,29 @@
-use clap::Parser;
-use dns_lookup::lookup_host;
-use std::io::{self, Write};
-use std::net::IpAddr;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::{Parser, ValueEnum};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, execute, queue};
+use mtr::{
+    check_pinger, create_pinger, header_len, payload, probe_hop, resolve_all, resolve_target, reverse_lookup, run_with_timeout, us_to_ms,
+    DnsResolver, ErrorPolicy, Family, HopStats, MtrSession, Outcome, Prober, ProbeResult, RouteChange, Rng, TraceConfig, Tracer, TtlSet,
+    DNS_DRAIN_TIMEOUT, MAX_PROBE_WORKERS,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc};
 use std::thread;
-use std::time::Instant;
-use winping::{Buffer, Error as PingError, Pinger};
+use std::time::{Duration, Instant};
+use winping::Buffer;
+
+#[cfg(feature = "geoip")]
+mod geoip;
+mod html;
+#[cfg(feature = "prometheus")]
+mod metrics;
 
 /// Windows MTR - Network diagnostic tool combining ping and traceroute
 #[derive(Parser, Debug)]
@@ -14,290 +31,2591 @@ use winping::{Buffer, Error as PingError, Pinger};
 #[command(version = "0.1.0")]
 #[command(about = "Windows MTR - traceroute and ping combined", long_about = None)]
 struct Args {
-    /// Target hostname or IP address
-    target: String,
+    /// Target hostnames or IP addresses
+    #[arg(required_unless_present_any = ["cidr", "targets_file"])]
+    targets: Vec<String>,
 
-    /// Number of pings per hop (0 = unlimited)
-    #[arg(short = 'c', long, default_value = "0")]
-    count: u32,
+    /// Trace every address a hostname resolves to, one table per address
+    #[arg(long = "resolve-all")]
+    resolve_all: bool,
+
+    /// Read more targets from a file: one per line, blank lines and # comments ignored
+    #[arg(long = "targets-file")]
+    targets_file: Option<String>,
 
-    /// Interval between cycles in milliseconds
-    #[arg(short = 'i', long, default_value = "500")]
+    /// Number of pings per hop (0 = unlimited); in report mode it takes precedence over --report-cycles
+    #[arg(short = 'c', long)]
+    count: Option<u32>,
+
+    /// Interval between cycles: milliseconds, or seconds with a decimal point (0.25)
+    #[arg(short = 'i', long, default_value = "500", value_parser = parse_millis)]
     interval: u64,
 
     /// Maximum number of hops (TTL)
     #[arg(short = 'm', long = "max-ttl", default_value = "30")]
     max_ttl: u8,
 
+    /// Only probe these TTLs, as a list of numbers and ranges (8-12 or 1,5,10)
+    #[arg(long, value_parser = parse_ttls)]
+    ttls: Option<TtlSet>,
+
+    /// Stop probing this many unanswered hops past the furthest known one; 0 probes up to --max-ttl
+    #[arg(short = 'U', long = "max-unknown", default_value = "5")]
+    max_unknown: u8,
+
+    /// First TTL to probe; earlier hops are skipped
+    #[arg(short = 'f', long = "first-ttl", default_value = "1", value_parser = clap::value_parser!(u8).range(1..))]
+    first_ttl: u8,
+
+    /// Send probes from this local address, to trace out of a specific interface
+    #[arg(short = 'a', long = "bind", alias = "address")]
+    bind: Option<IpAddr>,
+
+    /// Give up on a DNS lookup after this long: milliseconds, or seconds with a decimal point
+    #[arg(long = "dns-timeout", value_parser = parse_millis)]
+    dns_timeout: Option<u64>,
+
+    /// Load reverse DNS names from this file at startup and save new ones to it on exit
+    #[arg(long = "dns-cache")]
+    dns_cache: Option<String>,
+
+    /// Name hops from a TOML file whose [annotations] table maps addresses or CIDRs to labels, shown in place of reverse DNS
+    #[arg(long, value_name = "FILE.toml")]
+    annotations: Option<String>,
+
     /// Do not resolve hostnames
     #[arg(short = 'n', long = "no-dns")]
     no_dns: bool,
 
+    /// Stop probing and print the final report after this many seconds, whatever --count says
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    deadline: Option<u64>,
+
     /// Report mode: print final report and exit
     #[arg(short = 'r', long)]
     report: bool,
 
+    /// With -r, widen the host column to fit the longest name instead of truncating at 45 characters
+    #[arg(short = 'w', long = "wide", alias = "report-wide")]
+    wide: bool,
+
     /// Report mode cycle count
     #[arg(short = 'C', long = "report-cycles", default_value = "10")]
     report_cycles: u32,
 
-    /// Ping timeout in milliseconds
-    #[arg(short = 't', long, default_value = "500")]
+    /// Ping timeout: milliseconds, or seconds with a decimal point (1.5)
+    #[arg(short = 't', long, default_value = "500", value_parser = parse_timeout)]
     timeout: u32,
+
+    /// Probe size in bytes including IP and ICMP headers, as in mtr; negative picks a random size up to it per probe
+    #[arg(short = 's', long, default_value = "0", allow_negative_numbers = true,
+          value_parser = clap::value_parser!(i32).range(-65500..=65500))]
+    psize: i32,
+
+    /// Byte to fill the probe payload with (0-255); negative picks a random byte per probe
+    #[arg(short = 'B', long, default_value = "0", allow_negative_numbers = true,
+          value_parser = clap::value_parser!(i16).range(..=255))]
+    bitpattern: i16,
+
+    /// Re-probe a timed-out hop up to this many times per cycle before counting it lost
+    #[arg(long, default_value = "0")]
+    retries: u32,
+
+    /// Show two targets' tables side by side, marking hops whose loss or average RTT differ
+    #[arg(long, conflicts_with_all = ["cidr", "resolve_all", "trace"])]
+    compare: bool,
+
+    /// With --compare, mark hops whose loss differs by LOSS points or average RTT by RTT ms, and dim the rest
+    #[arg(long = "diff-threshold", value_name = "LOSS,RTT", requires = "compare", value_parser = parse_diff_threshold)]
+    diff_threshold: Option<DiffThreshold>,
+
+    /// Classic traceroute: probe each hop once, printing it as it answers, then exit
+    #[arg(long, alias = "once", conflicts_with_all = ["report", "csv_stream", "json_stream", "raw", "cidr"])]
+    trace: bool,
+
+    /// Resolve the targets and print the effective settings, then exit without sending a probe
+    #[arg(long, conflicts_with = "cidr")]
+    check: bool,
+
+    /// Ping every host in a CIDR block (e.g. 192.0.2.0/28) instead of tracing a target
+    #[arg(long, conflicts_with_all = ["targets", "targets_file"])]
+    cidr: Option<String>,
+
+    /// Sort order for the --cidr reachability table
+    #[arg(long, value_enum, default_value = "host")]
+    sort: SweepSort,
+
+    /// How ICMP errors from a hop (unreachable, packet too big) count toward loss
+    #[arg(long = "count-errors-as", value_enum, default_value = "lost")]
+    count_errors_as: ErrorPolicy,
+
+    /// Back off exponentially between cycles while no hop responds at all
+    #[arg(long)]
+    backoff: bool,
+
+    /// Upper bound for the --backoff delay in milliseconds
+    #[arg(long = "max-backoff", default_value = "30000")]
+    max_backoff: u64,
+
+    /// Probe worker threads shared by all targets and hops [default: one per probe, at most 64]
+    #[arg(long, visible_alias = "max-concurrent", value_parser = clap::value_parser!(u16).range(1..))]
+    concurrency: Option<u16>,
+
+    /// Send each cycle's probes as overlapped IcmpSendEcho2 requests from one I/O thread instead of a worker pool
+    #[arg(long, conflicts_with = "concurrency")]
+    overlapped: bool,
+
+    /// Randomize each pause between cycles by up to this percent of the interval, either way
+    #[arg(long = "interval-jitter", value_parser = clap::value_parser!(u8).range(0..=100))]
+    interval_jitter: Option<u8>,
+
+    /// Seed for --interval-jitter, so a capture's timing can be reproduced
+    #[arg(long, requires = "interval_jitter")]
+    seed: Option<u64>,
+
+    /// Stretch the interval to a multiple of the target's RTT on slow paths
+    #[arg(long)]
+    adaptive: bool,
+
+    /// Add a CI95 column (95% confidence half-width of Avg) to the final report
+    #[arg(long)]
+    ci: bool,
+
+    /// Print the final report as JSON instead of a text table
+    #[arg(long, requires = "report")]
+    json: bool,
+
+    /// Field layout of --json: this tool's own, or that of `mtr --json` for existing parsers
+    #[arg(long = "json-format", value_enum, default_value = "winmtr", requires = "json")]
+    json_format: JsonFormat,
+
+    /// Print the final report as CSV instead of a text table
+    #[arg(long, requires = "report", conflicts_with = "json")]
+    csv: bool,
+
+    /// In report mode, exit with 1 if target loss is at or above this percent (2 if never reached)
+    #[arg(long = "loss-threshold", requires = "report")]
+    loss_threshold: Option<f64>,
+
+    /// Show each hop's origin AS, looked up once per address via Team Cymru's DNS service
+    #[arg(short = 'z', long, visible_alias = "aslookup")]
+    asn: bool,
+
+    /// Show each hop's country and city from a MaxMind GeoLite2/GeoIP2 City database
+    #[cfg(feature = "geoip")]
+    #[arg(long, value_name = "MMDB")]
+    geoip: Option<String>,
+
+    /// Stream one CSV row per hop per cycle instead of the live table
+    #[arg(long = "csv-stream", conflicts_with_all = ["report", "json", "csv"])]
+    csv_stream: bool,
+
+    /// Stream one JSON object per cycle (NDJSON) with every hop's statistics instead of the live table
+    #[arg(long = "json-stream", conflicts_with_all = ["report", "json", "csv", "csv_stream", "raw"])]
+    json_stream: bool,
+
+    /// In the live view, ring the bell and highlight a hop when its loss rises above this percent
+    #[arg(long = "alert-loss", conflicts_with_all = ["report", "raw", "csv_stream", "json_stream"])]
+    alert_loss: Option<f64>,
+
+    /// Serve Prometheus metrics for every hop at http://0.0.0.0:<PORT>/metrics
+    #[cfg(feature = "prometheus")]
+    #[arg(long = "prometheus-port")]
+    prometheus_port: Option<u16>,
+
+    /// Append each cycle's table, uncolored and timestamped, to this file
+    #[arg(long = "log-file")]
+    log_file: Option<String>,
+
+    /// Print `mtr --raw` records (x/h/p/d lines) as probes complete instead of the live table
+    #[arg(long, conflicts_with_all = ["report", "csv_stream"])]
+    raw: bool,
+
+    /// When the run ends, write a self-contained HTML report with an RTT chart per hop to this file
+    #[arg(long)]
+    html: Option<String>,
+
+    /// Append timestamped --csv-stream rows to this file every cycle, alongside any display
+    #[arg(long = "csv-file")]
+    csv_file: Option<String>,
+
+    /// Add an ISO-8601 UTC timestamp column to each streamed cycle
+    #[arg(long, requires = "csv_stream")]
+    timestamp: bool,
+
+    /// Free-form label, such as a site name or ticket number, added to JSON, CSV and Prometheus output
+    #[arg(long, visible_alias = "probe-description", value_parser = parse_tag)]
+    tag: Option<String>,
+
+    /// Print the final report as XML in the layout of `mtr --xml`
+    #[arg(long, requires = "report", conflicts_with_all = ["json", "csv"])]
+    xml: bool,
+
+    /// Print the final report as a Markdown table, for pasting into issues and wikis
+    #[arg(long, requires = "report", conflicts_with_all = ["json", "csv", "xml"])]
+    markdown: bool,
+
+    /// Only use IPv4 addresses for the target
+    #[arg(short = '4', long, conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Only use IPv6 addresses for the target
+    #[arg(short = '6', long)]
+    ipv6: bool,
+
+    /// Add P50/P90/P95/P99 RTT columns to the final report
+    #[arg(long)]
+    percentiles: bool,
+
+    /// Number of recent RTT samples kept per hop for --percentiles
+    #[arg(long = "sample-window", default_value = "1000", value_parser = clap::value_parser!(u32).range(1..))]
+    sample_window: u32,
+
+    /// After the report, draw an RTT histogram of hop TTL, or of the last hop when none is given
+    #[arg(long, value_name = "TTL", num_args = 0..=1, require_equals = true, requires = "report",
+          value_parser = clap::value_parser!(u8).range(1..))]
+    histogram: Option<Option<u8>>,
+
+    /// Color the Loss% and Avg cells green, yellow or red by the thresholds below
+    #[arg(long, value_enum, default_value = "auto")]
+    color: ColorMode,
+
+    /// Loss percent above which the Loss% cell turns yellow
+    #[arg(long = "warn-loss", default_value = "0")]
+    warn_loss: f64,
+
+    /// Loss percent above which the Loss% cell turns red
+    #[arg(long = "crit-loss", default_value = "10")]
+    crit_loss: f64,
+
+    /// Average RTT in milliseconds above which the Avg cell turns yellow
+    #[arg(long = "warn-latency", default_value = "100")]
+    warn_latency: f64,
+
+    /// Average RTT in milliseconds above which the Avg cell turns red
+    #[arg(long = "crit-latency", default_value = "250")]
+    crit_latency: f64,
+
+    /// Compute loss and RTT statistics over each hop's last N probes instead of the whole run
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    window: Option<u32>,
+
+    /// Add a column with replies out of each hop's last N probes, e.g. 18/20
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    recent: Option<u16>,
+
+    /// Add a column sketching each hop's last RTTs with block characters; --sparkline=N sets how many (default 20)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "20",
+          value_parser = clap::value_parser!(u16).range(1..))]
+    sparkline: Option<u16>,
+
+    /// Live table layout to start in; d cycles through them while running
+    #[arg(long, value_enum, default_value = "statistics")]
+    display: DisplayMode,
+
+    /// Print a summary line under each table: hops, target loss and avg, summed hop averages
+    #[arg(long)]
+    summary: bool,
+
+    /// Statistics columns and their order, as mtr field letters (L S R D N A B W V M J X I)
+    #[arg(short = 'o', long = "order", default_value = "LSNABWVM", value_parser = parse_fields)]
+    fields: Fields,
+
+    /// Take the statistics columns from a named preset instead of -o; `f` cycles presets live
+    #[arg(long, value_enum, conflicts_with = "fields")]
+    preset: Option<Preset>,
+}
+
+impl Args {
+    fn family(&self) -> Family {
+        // A source address only reaches targets of its own family
+        if let Some(source) = self.bind { return if source.is_ipv4() { Family::V4 } else { Family::V6 }; }
+        if self.ipv4 { Family::V4 } else if self.ipv6 { Family::V6 } else { Family::Any }
+    }
+
+    /// Cycles to run before stopping: an explicit --count wins, report mode otherwise
+    /// runs --report-cycles, and the live view runs until interrupted
+    fn cycle_limit(&self) -> Option<u32> {
+        match self.count {
+            Some(0) => None,
+            Some(count) => Some(count),
+            None => self.report.then_some(self.report_cycles),
+        }
+    }
+
+    fn dns_timeout(&self) -> Option<Duration> {
+        self.dns_timeout.map(Duration::from_millis)
+    }
+
+    fn thresholds(&self) -> Thresholds {
+        Thresholds { warn_loss: self.warn_loss, crit_loss: self.crit_loss, warn_latency: self.warn_latency, crit_latency: self.crit_latency }
+    }
+}
+
+/// Levels at which the Loss% and Avg cells turn from green to yellow to red
+#[derive(Clone, Copy, Debug)]
+struct Thresholds {
+    warn_loss: f64,
+    crit_loss: f64,
+    warn_latency: f64,
+    crit_latency: f64,
+}
+
+impl Thresholds {
+    /// ANSI foreground code for this hop's cell in `field`, if that column is colored
+    fn color(&self, field: Field, hop: &HopStats) -> Option<&'static str> {
+        let level = |value: f64, warn: f64, crit: f64| if value > crit { "31" } else if value > warn { "33" } else { "32" };
+        match field {
+            Field::Loss if hop.sent > 0 => Some(level(hop.loss_percent(), self.warn_loss, self.crit_loss)),
+            Field::Avg if hop.received > 0 => Some(level(hop.avg_rtt(), self.warn_latency, self.crit_latency)),
+            _ => None,
+        }
+    }
+}
+
+/// When to emit ANSI colors
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ColorMode {
+    /// Only when stdout is a terminal and NO_COLOR is unset
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    fn enabled(self) -> bool {
+        match self {
+            ColorMode::Auto => io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+/// Document layout for --json
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum JsonFormat {
+    /// Every statistic, with RTTs in milliseconds and nulls for undefined values
+    Winmtr,
+    /// `{"report": {"mtr": {...}, "hubs": [...]}}` as written by `mtr --json`
+    Mtr,
+}
+
+/// Row ordering for the CIDR sweep table
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SweepSort {
+    Host,
+    Loss,
+    Avg,
+}
+
+/// Largest number of hosts a single --cidr sweep may cover
+const MAX_SWEEP_HOSTS: usize = 255;
+
+/// The --json document: one report per traced address, an array even for a single target
+#[derive(Serialize)]
+struct JsonReports {
+    traces: Vec<Report>,
 }
 
-/// Statistics for a single hop
-#[derive(Clone)]
-struct HopStats {
+/// Machine-readable snapshot of a finished run
+#[derive(Serialize)]
+struct Report {
+    target: String,
+    target_ip: IpAddr,
+    /// Local address probes were sent from, when --bind chose one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<IpAddr>,
+    /// The run's --tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+    hops: Vec<HopReport>,
+}
+
+/// Per-hop statistics with RTTs in milliseconds; undefined values are null
+#[derive(Serialize)]
+struct HopReport {
     ttl: u8,
     ip: Option<IpAddr>,
     hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<String>,
+    ips: Vec<ObservedIp>,
     sent: u32,
     received: u32,
-    last_rtt: Option<u32>,
-    min_rtt: Option<u32>,
-    max_rtt: Option<u32>,
-    sum_rtt: u64,
-    sum_rtt_sq: u64,
+    loss_percent: f64,
+    last_rtt: Option<f64>,
+    avg_rtt: Option<f64>,
+    min_rtt: Option<f64>,
+    max_rtt: Option<f64>,
+    std_dev: Option<f64>,
+    jitter: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    p50: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    p90: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    p95: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    p99: Option<f64>,
+    in_loop: bool,
+    /// Switches of primary responder over the run, absent while there are none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    route_change_count: Option<u32>,
+    /// The most recent switches, at most ROUTE_CHANGE_HISTORY of them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    route_changes: Vec<RouteChangeReport>,
+}
+
+/// A switch of a hop's primary (most frequent) responder, timestamped in UTC
+#[derive(Serialize)]
+struct RouteChangeReport {
+    time: String,
+    from: IpAddr,
+    to: IpAddr,
+}
+
+impl From<&RouteChange> for RouteChangeReport {
+    fn from(change: &RouteChange) -> Self {
+        let time: DateTime<Utc> = change.at.into();
+        Self { time: time.to_rfc3339_opts(SecondsFormat::Millis, true), from: change.from, to: change.to }
+    }
+}
+
+/// A report in the `mtr --json` layout
+#[derive(Serialize)]
+struct MtrJson {
+    report: MtrJsonReport,
+}
+
+#[derive(Serialize)]
+struct MtrJsonReport {
+    mtr: MtrJsonHeader,
+    hubs: Vec<MtrJsonHub>,
+}
+
+#[derive(Serialize)]
+struct MtrJsonHeader {
+    src: String,
+    dst: String,
+    tos: u8,
+    tests: u32,
+    psize: String,
+    bitpattern: String,
+}
+
+/// One hop with mtr's own column names; undefined RTTs are 0 as in mtr
+#[derive(Serialize)]
+struct MtrJsonHub {
+    count: u8,
+    host: String,
+    #[serde(rename = "ASN", skip_serializing_if = "Option::is_none")]
+    asn: Option<String>,
+    #[serde(rename = "Loss%")]
+    loss: f64,
+    #[serde(rename = "Snt")]
+    sent: u32,
+    #[serde(rename = "Last")]
+    last: f64,
+    #[serde(rename = "Avg")]
+    avg: f64,
+    #[serde(rename = "Best")]
+    best: f64,
+    #[serde(rename = "Wrst")]
+    worst: f64,
+    #[serde(rename = "StDev")]
+    std_dev: f64,
+}
+
+/// One --json-stream line: every trace's hops as of the end of a cycle, with only that cycle's route changes
+#[derive(Serialize)]
+struct CycleEvent {
+    cycle: u32,
+    timestamp: String,
+    traces: Vec<Report>,
 }
 
-impl HopStats {
-    fn new(ttl: u8) -> Self {
+impl CycleEvent {
+    fn new(cycle: u32, traces: &[Tracer], source: Option<IpAddr>, tag: Option<&str>) -> Self {
         Self {
-            ttl,
-            ip: None,
-            hostname: None,
-            sent: 0,
-            received: 0,
-            last_rtt: None,
-            min_rtt: None,
-            max_rtt: None,
-            sum_rtt: 0,
-            sum_rtt_sq: 0,
+            cycle,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            traces: traces.iter().map(|trace| {
+                let hops = trace.visible_hops();
+                let mut report = Report::new(&trace.target, trace.target_ip, source, &hops).tagged(tag);
+                // Only this cycle's switch, so a long stream does not repeat each hop's history on every line
+                for (hop, stats) in report.hops.iter_mut().zip(&hops) {
+                    hop.route_changes = stats.route_changes.back().filter(|_| stats.route_changed).map(RouteChangeReport::from).into_iter().collect();
+                }
+                report
+            }).collect(),
+        }
+    }
+}
+
+/// One responder seen at a hop and how many times it answered
+#[derive(Serialize)]
+struct ObservedIp {
+    ip: IpAddr,
+    count: u32,
+}
+
+impl Report {
+    fn new(target: &str, target_ip: IpAddr, source: Option<IpAddr>, hops: &[HopStats]) -> Self {
+        Self { target: target.to_string(), target_ip, source, tag: None, hops: hops.iter().map(HopReport::from).collect() }
+    }
+
+    fn tagged(mut self, tag: Option<&str>) -> Self {
+        self.tag = tag.map(str::to_string);
+        self
+    }
+
+    /// One CSV row per hop, to follow CSV_HEADER and a tag column if tagged; missing values are empty cells
+    fn csv_rows(&self) -> String {
+        let cell = |v: Option<f64>| v.map_or(String::new(), |v| format!("{:.1}", v));
+        let mut out = String::new();
+        for hop in &self.hops {
+            if let Some(tag) = &self.tag { out.push_str(&csv_quote(tag)); out.push(','); }
+            out.push_str(&format!(
+                "{},{},{},{},{},{:.1},{},{},{},{},{},{},{}\n",
+                csv_quote(&self.target), self.target_ip, hop.ttl, hop.ip.map_or(String::new(), |ip| ip.to_string()),
+                hop.hostname.as_deref().map_or(String::new(), csv_quote),
+                hop.loss_percent, hop.sent, cell(hop.last_rtt), cell(hop.avg_rtt),
+                cell(hop.min_rtt), cell(hop.max_rtt), cell(hop.std_dev), cell(hop.jitter)
+            ));
+        }
+        out
+    }
+
+    /// A heading line and a Markdown table with one row per hop
+    fn to_markdown(&self) -> String {
+        let cell = |v: Option<f64>| v.map_or("---".to_string(), |v| format!("{:.1}", v));
+        let mut out = format!("**mtr to {} ({})**\n\n", markdown_escape(&self.target), self.target_ip);
+        out.push_str("| Hop | Host | Loss% | Snt | Last | Avg | Best | Wrst | StDev | Javg |\n");
+        out.push_str("|----:|------|------:|----:|-----:|----:|-----:|-----:|------:|-----:|\n");
+        for hop in &self.hops {
+            let host = match (hop.ip, &hop.hostname) {
+                (Some(ip), Some(hostname)) => format!("{} ({})", markdown_escape(hostname), ip),
+                (Some(ip), None) => ip.to_string(),
+                (None, _) => "???".to_string(),
+            };
+            out.push_str(&format!(
+                "| {} | {} | {:.1}% | {} | {} | {} | {} | {} | {} | {} |\n",
+                hop.ttl, host, hop.loss_percent, hop.sent, cell(hop.last_rtt), cell(hop.avg_rtt),
+                cell(hop.min_rtt), cell(hop.max_rtt), cell(hop.std_dev), cell(hop.jitter)
+            ));
         }
+        out
+    }
+
+    /// The -s value as mtr reports it; without one, probes are bare IP and ICMP headers
+    fn packet_size(&self, psize: i32) -> i32 {
+        if psize == 0 { header_len(self.target_ip) as i32 } else { psize }
+    }
+
+    /// The -B value as mtr reports it: the fill byte, or a marker when each probe picks a random one
+    fn bitpattern(bitpattern: i16) -> String {
+        if bitpattern < 0 { "rand(0x00-FF)".to_string() } else { format!("0x{:02X}", bitpattern) }
+    }
+
+    /// Same document shape and key names as `mtr --json`, so its parsers read it unchanged
+    fn to_mtr_json(&self, tests: u32, psize: i32, bitpattern: i16) -> MtrJson {
+        let ms = |v: Option<f64>| v.map_or(0.0, |v| (v * 100.0).round() / 100.0);
+        let hubs = self.hops.iter().map(|hop| MtrJsonHub {
+            count: hop.ttl,
+            host: hop.display_host(),
+            asn: hop.asn.clone(),
+            loss: hop.loss_percent,
+            sent: hop.sent,
+            last: ms(hop.last_rtt),
+            avg: ms(hop.avg_rtt),
+            best: ms(hop.min_rtt),
+            worst: ms(hop.max_rtt),
+            std_dev: ms(hop.std_dev),
+        }).collect();
+        let mtr = MtrJsonHeader {
+            src: local_host_name(),
+            dst: self.target.clone(),
+            tos: 0,
+            tests,
+            psize: self.packet_size(psize).to_string(),
+            bitpattern: Self::bitpattern(bitpattern),
+        };
+        MtrJson { report: MtrJsonReport { mtr, hubs } }
     }
 
-    fn record_response(&mut self, ip: IpAddr, rtt: u32) {
-        self.ip = Some(ip);
-        self.sent += 1;
-        self.received += 1;
-        self.last_rtt = Some(rtt);
-        self.sum_rtt += rtt as u64;
-        self.sum_rtt_sq += (rtt as u64) * (rtt as u64);
-        self.min_rtt = Some(self.min_rtt.map_or(rtt, |m| m.min(rtt)));
-        self.max_rtt = Some(self.max_rtt.map_or(rtt, |m| m.max(rtt)));
+    /// Same element and field names as `mtr --xml`, which strict downstream parsers expect
+    fn to_xml(&self, tests: u32, psize: i32, bitpattern: i16) -> String {
+        // Hostnames can be non-ASCII, and mtr declares the encoding for the same reason
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        out.push_str(&format!(
+            "<MTR SRC=\"{}\" DST=\"{}\" TOS=\"0x0\" PSIZE=\"{}\" BITPATTERN=\"{}\" TESTS=\"{}\">\n",
+            xml_escape(&local_host_name()), xml_escape(&self.target), self.packet_size(psize), Self::bitpattern(bitpattern), tests
+        ));
+        let ms = |v: Option<f64>| format!("{:.2}", v.unwrap_or(0.0));
+        for hop in &self.hops {
+            let host = hop.display_host();
+            out.push_str(&format!("    <HUB COUNT=\"{}\" HOST=\"{}\">\n", hop.ttl, xml_escape(&host)));
+            let fields = [
+                ("Loss%", format!("{:.1}%", hop.loss_percent)),
+                ("Snt", hop.sent.to_string()),
+                ("Last", ms(hop.last_rtt)),
+                ("Avg", ms(hop.avg_rtt)),
+                ("Best", ms(hop.min_rtt)),
+                ("Wrst", ms(hop.max_rtt)),
+                ("StDev", ms(hop.std_dev)),
+            ];
+            for (name, value) in fields {
+                out.push_str(&format!("        <{0}>{1}</{0}>\n", name, value));
+            }
+            out.push_str("    </HUB>\n");
+        }
+        out.push_str("</MTR>\n");
+        out
     }
+}
 
-    fn record_timeout(&mut self) {
-        self.sent += 1;
+impl HopReport {
+    /// Hostname when known, else the address, else mtr's `???`
+    fn display_host(&self) -> String {
+        self.hostname.clone().or_else(|| self.ip.map(|ip| ip.to_string())).unwrap_or_else(|| "???".to_string())
     }
+}
+
+/// This machine's name, which mtr reports as the trace's source
+fn local_host_name() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_string())
+}
+
+/// Escape text for use inside an XML attribute
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Column names for the rows produced by Report::csv_rows
+const CSV_HEADER: &str = "target,target_ip,ttl,ip,hostname,loss_percent,sent,last,avg,best,worst,stdev,jitter\n";
 
-    fn loss_percent(&self) -> f64 {
-        if self.sent == 0 { 0.0 } else { ((self.sent - self.received) as f64 / self.sent as f64) * 100.0 }
+/// Column names for --csv-stream rows, after the optional timestamp and tag columns
+const CSV_STREAM_HEADER: &str = "cycle,target,target_ip,ttl,ip,rtt,lost,loss_percent\n";
+
+/// This cycle's outcome for each displayed hop: its RTT (empty when lost) and the loss so far
+fn csv_stream_rows(trace: &Tracer, cycle: u32, timestamp: Option<&str>, tag: Option<&str>) -> String {
+    let mut out = String::new();
+    for hop in trace.visible_hops() {
+        let rtt = if hop.replied { hop.last_rtt.map_or(String::new(), |r| format!("{:.1}", us_to_ms(r))) } else { String::new() };
+        if let Some(ts) = timestamp { out.push_str(ts); out.push(','); }
+        if let Some(tag) = tag { out.push_str(&csv_quote(tag)); out.push(','); }
+        out.push_str(&format!(
+            "{},{},{},{},{},{},{},{:.1}\n",
+            cycle, csv_quote(&trace.target), trace.target_ip, hop.ttl, hop.ip.map_or(String::new(), |ip| ip.to_string()),
+            rtt, u8::from(!hop.replied), hop.loss_percent()
+        ));
     }
+    out
+}
 
-    fn avg_rtt(&self) -> f64 {
-        if self.received == 0 { 0.0 } else { self.sum_rtt as f64 / self.received as f64 }
+/// Line records in the `mtr --raw` format; `pos` is the 0-based hop index
+/// - `x <pos> <seq>`: probe sent
+/// - `h <pos> <ip>`: responder seen at the hop, repeated when it changes
+/// - `p <pos> <usec> <seq>`: reply with its RTT in microseconds
+/// - `d <pos> <name>`: reverse DNS name of the hop
+#[derive(Default)]
+struct RawWriter {
+    seq: u32,
+    hosts: HashMap<u8, IpAddr>,
+    names: HashMap<u8, String>,
+}
+
+impl RawWriter {
+    fn probe(&mut self, ttl: u8, result: &ProbeResult, policy: ErrorPolicy) -> String {
+        let pos = ttl - 1;
+        self.seq += 1;
+        let mut out = format!("x {} {}\n", pos, self.seq);
+        let (ip, rtt) = match *result {
+            ProbeResult::Reply { ip, rtt } | ProbeResult::TtlExpired { ip, rtt } => (ip, Some(rtt)),
+            ProbeResult::Unreachable { ip, rtt } => (ip, matches!(policy, ErrorPolicy::Responded).then_some(rtt)),
+            ProbeResult::Timeout => return out,
+        };
+        if self.hosts.insert(pos, ip) != Some(ip) { out.push_str(&format!("h {} {}\n", pos, ip)); }
+        if let Some(rtt) = rtt { out.push_str(&format!("p {} {} {}\n", pos, rtt, self.seq)); }
+        out
     }
 
-    fn std_dev(&self) -> f64 {
-        if self.received < 2 { 0.0 } else {
-            let n = self.received as f64;
-            let mean = self.avg_rtt();
-            let variance = (self.sum_rtt_sq as f64 / n) - (mean * mean);
-            if variance > 0.0 { variance.sqrt() } else { 0.0 }
+    /// `d` records for hostnames resolved since the last call
+    fn names(&mut self, hops: &[HopStats]) -> String {
+        let mut out = String::new();
+        for hop in hops {
+            let Some(name) = &hop.hostname else { continue };
+            let pos = hop.ttl - 1;
+            if self.names.get(&pos) != Some(name) {
+                self.names.insert(pos, name.clone());
+                out.push_str(&format!("d {} {}\n", pos, name));
+            }
         }
+        out
     }
 }
 
-#[derive(Clone)]
-enum ProbeResult {
-    Reply { ip: IpAddr, rtt: u32 },
-    TtlExpired { ip: IpAddr, rtt: u32 },
-    Unreachable { ip: IpAddr },
-    Timeout,
+/// Escape a table cell so a `|` in a hostname does not split the column
+fn markdown_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+/// Quote a CSV field, doubling any embedded quotes
+fn csv_quote(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
 }
 
-fn resolve_target(target: &str) -> Result<IpAddr, String> {
-    if let Ok(ip) = target.parse::<IpAddr>() { return Ok(ip); }
-    match lookup_host(target) {
-        Ok(ips) => {
-            for ip in &ips { if ip.is_ipv4() { return Ok(*ip); } }
-            ips.into_iter().next().ok_or_else(|| format!("No IP found for {}", target))
+impl From<&HopStats> for HopReport {
+    fn from(hop: &HopStats) -> Self {
+        Self {
+            ttl: hop.ttl,
+            ip: hop.ip,
+            hostname: hop.hostname.clone(),
+            label: hop.label.clone(),
+            asn: hop.asn.clone(),
+            location: hop.location.clone(),
+            ips: hop.ips.iter().map(|&(ip, count)| ObservedIp { ip, count }).collect(),
+            sent: hop.sent,
+            received: hop.received,
+            loss_percent: hop.loss_percent(),
+            last_rtt: hop.last_rtt.map(us_to_ms),
+            avg_rtt: (hop.received > 0).then(|| hop.avg_rtt()),
+            min_rtt: hop.min_rtt.map(us_to_ms),
+            max_rtt: hop.max_rtt.map(us_to_ms),
+            std_dev: (hop.received > 1).then(|| hop.std_dev()),
+            jitter: (hop.received > 1).then(|| hop.jitter()),
+            p50: hop.percentile(50.0),
+            p90: hop.percentile(90.0),
+            p95: hop.percentile(95.0),
+            p99: hop.percentile(99.0),
+            in_loop: hop.in_loop,
+            route_change_count: (hop.route_change_count > 0).then_some(hop.route_change_count),
+            route_changes: hop.route_changes.iter().map(RouteChangeReport::from).collect(),
         }
-        Err(e) => Err(format!("Failed to resolve {}: {}", target, e)),
     }
 }
 
-fn reverse_lookup(ip: IpAddr) -> Option<String> {
-    if let Ok(hosts) = dns_lookup::lookup_addr(&ip) {
-        if !hosts.is_empty() && hosts != ip.to_string() { return Some(hosts); }
+/// Targets listed in a --targets-file, skipping blank lines and # comments
+fn parse_targets(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Names in a --dns-cache file, one "ip hostname" pair per line; unreadable lines are skipped
+fn parse_dns_cache(text: &str) -> Vec<(IpAddr, String)> {
+    text.lines().filter_map(|line| {
+        let mut parts = line.split_whitespace();
+        let ip = parts.next()?.parse().ok()?;
+        let name = parts.next()?;
+        parts.next().is_none().then(|| (ip, name.to_string()))
+    }).collect()
+}
+
+/// One --annotations entry: every address within `prefix` bits of `bits` gets `label`
+#[derive(Debug, PartialEq)]
+struct Annotation {
+    /// Address bits, IPv4 shifted into the top 32 so both families share one mask
+    bits: u128,
+    prefix: u32,
+    v4: bool,
+    label: String,
+}
+
+fn addr_bits(ip: IpAddr) -> (u128, bool) {
+    match ip {
+        IpAddr::V4(ip) => ((u32::from(ip) as u128) << 96, true),
+        IpAddr::V6(ip) => (u128::from(ip), false),
+    }
+}
+
+/// Layout of an --annotations TOML file: an `[annotations]` table of address or CIDR keys to labels
+#[derive(Deserialize)]
+struct AnnotationsFile {
+    annotations: BTreeMap<String, String>,
+}
+
+/// Entries of an --annotations TOML file
+fn parse_annotations(text: &str) -> Result<Vec<Annotation>, String> {
+    let file: AnnotationsFile = toml::from_str(text).map_err(|e| e.to_string())?;
+    file.annotations.into_iter().map(|(network, label)| {
+        let invalid = |why: String| format!("annotation \"{}\": {}", network, why);
+        let (addr, prefix) = network.split_once('/').map_or((network.as_str(), None), |(addr, prefix)| (addr, Some(prefix)));
+        let addr: IpAddr = addr.parse().map_err(|_| invalid(format!("invalid address {}", addr)))?;
+        let (bits, v4) = addr_bits(addr);
+        let width = if v4 { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u32>().ok().filter(|&p| p <= width).ok_or_else(|| invalid(format!("invalid prefix /{}", prefix)))?,
+            None => width,
+        };
+        Ok(Annotation { bits, prefix, v4, label })
+    }).collect()
+}
+
+/// Label of the most specific annotation covering `ip`
+fn annotation_for(annotations: &[Annotation], ip: IpAddr) -> Option<String> {
+    let (bits, v4) = addr_bits(ip);
+    annotations.iter()
+        .filter(|a| a.v4 == v4 && (a.bits ^ bits) & u128::MAX.checked_shl(128 - a.prefix).unwrap_or(0) == 0)
+        .max_by_key(|a| a.prefix)
+        .map(|a| a.label.clone())
+}
+
+fn write_dns_cache(path: &str, names: &mut [(IpAddr, String)]) -> io::Result<()> {
+    names.sort();
+    let mut file = File::create(path)?;
+    for (ip, name) in names.iter() { writeln!(file, "{} {}", ip, name)?; }
+    file.flush()
+}
+
+/// Parse a --ttls list such as "1,5,10" or "8-12"
+fn parse_ttls(spec: &str) -> Result<TtlSet, String> {
+    let mut ttls = TtlSet::default();
+    for part in spec.split(',').map(str::trim) {
+        let parse = |ttl: &str| match ttl.trim().parse::<u8>() {
+            Ok(0) | Err(_) => Err(format!("invalid TTL '{}' in {}", ttl.trim(), spec)),
+            Ok(ttl) => Ok(ttl),
+        };
+        let (low, high) = match part.split_once('-') {
+            Some((low, high)) => (parse(low)?, parse(high)?),
+            None => (parse(part)?, parse(part)?),
+        };
+        if low > high { return Err(format!("TTL range {} runs backwards", part)); }
+        for ttl in low..=high { ttls.insert(ttl); }
     }
-    None
+    Ok(ttls)
 }
 
-fn format_hop(hop: &HopStats, no_dns: bool) -> String {
-    let host_str = match (&hop.ip, &hop.hostname) {
-        (Some(ip), Some(hostname)) if !no_dns => format!("{} ({})", hostname, ip),
-        (Some(ip), _) => ip.to_string(),
-        (None, _) => "???".to_string(),
-    };
-    let last = hop.last_rtt.map_or("---".to_string(), |r| format!("{:.1}", r as f64));
-    let avg = if hop.received > 0 { format!("{:.1}", hop.avg_rtt()) } else { "---".to_string() };
-    let best = hop.min_rtt.map_or("---".to_string(), |r| format!("{:.1}", r as f64));
-    let wrst = hop.max_rtt.map_or("---".to_string(), |r| format!("{:.1}", r as f64));
-    let stdev = if hop.received > 1 { format!("{:.1}", hop.std_dev()) } else { "---".to_string() };
-    format!(
-        "{:>3}. {:<45} {:>5.1}% {:>5} {:>6} {:>6} {:>6} {:>6} {:>6}",
-        hop.ttl, if host_str.len() > 45 { &host_str[..45] } else { &host_str },
-        hop.loss_percent(), hop.sent, last, avg, best, wrst, stdev
-    )
-}
-
-/// Probe a single hop - designed to run in a thread
-fn probe_hop(target: IpAddr, ttl: u8, timeout: u32) -> (u8, ProbeResult) {
-    let pinger = match Pinger::new() {
-        Ok(mut p) => { p.set_ttl(ttl); p.set_timeout(timeout); p }
-        Err(_) => return (ttl, ProbeResult::Timeout),
+/// Longest --tag accepted, so a stray paste cannot bloat every record
+const MAX_TAG_LEN: usize = 64;
+
+fn parse_tag(value: &str) -> Result<String, String> {
+    let len = value.chars().count();
+    if len > MAX_TAG_LEN { return Err(format!("tag is {} characters long; at most {} are allowed", len, MAX_TAG_LEN)); }
+    Ok(value.to_string())
+}
+
+/// Parse a duration given as integer milliseconds ("250") or decimal seconds ("0.25")
+fn parse_millis(value: &str) -> Result<u64, String> {
+    if !value.contains('.') {
+        return value.parse::<u64>().map_err(|e| format!("invalid milliseconds {}: {}", value, e));
+    }
+    let secs = value.parse::<f64>().map_err(|e| format!("invalid seconds {}: {}", value, e))?;
+    if !secs.is_finite() || secs < 0.0 || secs * 1000.0 > u64::MAX as f64 {
+        return Err(format!("{} seconds is out of range", value));
+    }
+    Ok((secs * 1000.0).round() as u64)
+}
+
+/// parse_millis narrowed to the u32 range IcmpSendEcho accepts
+fn parse_timeout(value: &str) -> Result<u32, String> {
+    let ms = parse_millis(value)?;
+    u32::try_from(ms).map_err(|_| format!("{} ms is too long for a ping timeout", ms))
+}
+
+/// Expand a CIDR block into its host addresses, skipping IPv4 network/broadcast addresses
+/// and the IPv6 Subnet-Router anycast address (RFC 4291), which no host answers for
+fn parse_cidr(spec: &str) -> Result<Vec<IpAddr>, String> {
+    let (addr, prefix) = spec.split_once('/').ok_or_else(|| format!("Invalid CIDR {}: missing /prefix", spec))?;
+    let addr = addr.parse::<IpAddr>().map_err(|e| format!("Invalid CIDR {}: {}", spec, e))?;
+    let prefix = prefix.parse::<u32>().map_err(|e| format!("Invalid CIDR {}: {}", spec, e))?;
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix > bits { return Err(format!("Invalid CIDR {}: prefix exceeds {} bits", spec, bits)); }
+    let host_bits = bits - prefix;
+    let too_large = || format!("CIDR {} is too large; sweeps are limited to {} hosts", spec, MAX_SWEEP_HOSTS);
+    if host_bits > 8 { return Err(too_large()); }
+    let hosts: Vec<IpAddr> = match addr {
+        IpAddr::V4(ip) => {
+            let base = u32::from(ip) & (u32::MAX.checked_shl(host_bits).unwrap_or(0));
+            let size = 1u32 << host_bits;
+            let range = if host_bits >= 2 { 1..size - 1 } else { 0..size };
+            range.map(|i| IpAddr::V4(Ipv4Addr::from(base + i))).collect()
+        }
+        IpAddr::V6(ip) => {
+            let base = u128::from(ip) & (u128::MAX.checked_shl(host_bits).unwrap_or(0));
+            // A /127 point-to-point link uses both addresses (RFC 6164)
+            let first = if host_bits >= 2 { 1 } else { 0 };
+            (first..1u128 << host_bits).map(|i| IpAddr::V6(Ipv6Addr::from(base + i))).collect()
+        }
     };
-    
-    let mut buffer = Buffer::new();
-    let start = Instant::now();
-    
-    match pinger.send(target, &mut buffer) {
-        Ok(rtt) => (ttl, ProbeResult::Reply { ip: target, rtt }),
-        Err(PingError::TtlExpired) => {
-            let elapsed = start.elapsed().as_millis() as u32;
-            if let Some(ip) = buffer.responding_ip() {
-                (ttl, ProbeResult::TtlExpired { ip, rtt: elapsed })
-            } else {
-                (ttl, ProbeResult::Timeout)
-            }
+    if hosts.len() > MAX_SWEEP_HOSTS { return Err(too_large()); }
+    Ok(hosts)
+}
+
+/// Statistics column of the hop table, selected by its `mtr -o` letter
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Loss,
+    Sent,
+    Received,
+    Dropped,
+    Last,
+    Avg,
+    Best,
+    Worst,
+    StDev,
+    /// Mean, latest, worst and RFC 3550 interarrival jitter, as in mtr
+    Jitter,
+    JitterLast,
+    JitterMax,
+    JitterInterarrival,
+    /// Replies out of the last N probes, added by --recent
+    Recent(usize),
+    /// RTT trend over the last N probes, added by --sparkline rather than a letter
+    Sparkline(usize),
+    /// Outcome of each of the last N probes, shown by the pattern display mode
+    Pattern(usize),
+    /// Final report extras, added by --ci and --percentiles, and Changes once a route changed
+    Ci95,
+    P50,
+    P90,
+    P95,
+    P99,
+    Changes,
+}
+
+impl Field {
+    fn from_letter(letter: char) -> Option<Self> {
+        Some(match letter {
+            'L' => Field::Loss,
+            'S' => Field::Sent,
+            'R' => Field::Received,
+            'D' => Field::Dropped,
+            'N' => Field::Last,
+            'A' => Field::Avg,
+            'B' => Field::Best,
+            'W' => Field::Worst,
+            'V' => Field::StDev,
+            'M' => Field::Jitter,
+            'J' => Field::JitterLast,
+            'X' => Field::JitterMax,
+            'I' => Field::JitterInterarrival,
+            _ => return None,
+        })
+    }
+
+    /// Every letter from_letter accepts, space separated, for error messages
+    fn letters() -> String {
+        ('A'..='Z').filter(|&c| Field::from_letter(c).is_some()).map(String::from).collect::<Vec<_>>().join(" ")
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Field::Loss => "Loss%",
+            Field::Sent => "Snt",
+            Field::Received => "Rcv",
+            Field::Dropped => "Drop",
+            Field::Last => "Last",
+            Field::Avg => "Avg",
+            Field::Best => "Best",
+            Field::Worst => "Wrst",
+            Field::StDev => "StDev",
+            Field::Jitter => "Javg",
+            Field::JitterLast => "Jttr",
+            Field::JitterMax => "Jmax",
+            Field::JitterInterarrival => "Jint",
+            Field::Recent(_) => "Hits",
+            Field::Sparkline(_) => "Recent",
+            Field::Pattern(_) => "History",
+            Field::Ci95 => "CI95",
+            Field::P50 => "P50",
+            Field::P90 => "P90",
+            Field::P95 => "P95",
+            Field::P99 => "P99",
+            Field::Changes => "Chg",
         }
-        Err(PingError::Timeout) => (ttl, ProbeResult::Timeout),
-        Err(PingError::HostUnreachable) | Err(PingError::NetUnreachable) => {
-            if let Some(ip) = buffer.responding_ip() {
-                (ttl, ProbeResult::Unreachable { ip })
-            } else {
-                (ttl, ProbeResult::Timeout)
+    }
+
+    /// Cell text, right-aligned to the column's width
+    fn cell(self, hop: &HopStats) -> String {
+        let ms = |v: Option<f64>| format!("{:>6}", v.map_or("---".to_string(), |v| format!("{:.1}", v)));
+        match self {
+            Field::Loss => format!("{:>5.1}%", hop.loss_percent()),
+            Field::Sent => format!("{:>5}", hop.sent),
+            Field::Received => format!("{:>5}", hop.received),
+            Field::Dropped => format!("{:>5}", hop.sent - hop.received),
+            Field::Last => ms(hop.last_rtt.map(us_to_ms)),
+            Field::Avg => ms((hop.received > 0).then(|| hop.avg_rtt())),
+            Field::Best => ms(hop.min_rtt.map(us_to_ms)),
+            Field::Worst => ms(hop.max_rtt.map(us_to_ms)),
+            Field::StDev => ms((hop.received > 1).then(|| hop.std_dev())),
+            Field::Jitter => ms((hop.received > 1).then(|| hop.jitter())),
+            Field::JitterLast => ms(hop.last_jitter()),
+            Field::JitterMax => ms(hop.max_jitter()),
+            Field::JitterInterarrival => ms((hop.received > 1).then(|| hop.interarrival_jitter())),
+            Field::Recent(n) => {
+                let recent = hop.history.iter().rev().take(n);
+                let sent = recent.clone().count();
+                format!("{:>1$}", format!("{}/{}", recent.filter(|outcome| outcome.rtt().is_some()).count(), sent), self.width())
             }
+            Field::Sparkline(n) => format!("{:>1$}", sparkline(hop, n), n),
+            Field::Pattern(n) => format!("{:>1$}", loss_pattern(hop, n), n),
+            Field::Ci95 => ms((hop.received > 1).then(|| hop.ci95())),
+            Field::P50 => ms(hop.percentile(50.0)),
+            Field::P90 => ms(hop.percentile(90.0)),
+            Field::P95 => ms(hop.percentile(95.0)),
+            Field::P99 => ms(hop.percentile(99.0)),
+            Field::Changes => format!("{:>5}", hop.route_change_count),
         }
-        Err(_) => (ttl, ProbeResult::Timeout),
     }
-}
 
-fn refresh_display(target: &str, target_ip: IpAddr, hops: &[HopStats], display_count: usize, no_dns: bool, lines_to_clear: usize) {
-    if lines_to_clear > 0 {
-        print!("\x1B[{}A\x1B[J", lines_to_clear);
+    fn width(self) -> usize {
+        match self {
+            Field::Sent | Field::Received | Field::Dropped | Field::Changes => 5,
+            Field::Recent(n) => (2 * n.to_string().len() + 1).max(4),
+            Field::Sparkline(n) | Field::Pattern(n) => n,
+            _ => 6,
+        }
     }
-    println!("mtr to {} ({})", target, target_ip);
-    println!("{:>3} {:<45} {:>6} {:>5} {:>6} {:>6} {:>6} {:>6} {:>6}", "", "Host", "Loss%", "Snt", "Last", "Avg", "Best", "Wrst", "StDev");
-    for hop in hops.iter().take(display_count) { println!("{}", format_hop(hop, no_dns)); }
-    io::stdout().flush().unwrap();
 }
 
-fn main() {
-    let args = Args::parse();
+/// Probe outcomes shown by the latency display mode's sparkline, and by --sparkline without a count
+const SPARKLINE_SAMPLES: usize = 20;
 
-    let target_ip = match resolve_target(&args.target) {
-        Ok(ip) => ip,
-        Err(e) => { eprintln!("Error: {}", e); std::process::exit(1); }
+/// One block glyph for each of the last `samples` probes, scaled between the hop's best and worst RTT; lost probes are blank.
+/// Every glyph is a single-width char, so `{:>N}` padding by char count keeps columns aligned.
+fn sparkline(hop: &HopStats, samples: usize) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    // --recent may keep a longer history than the column shows
+    let shown = hop.history.iter().skip(hop.history.len().saturating_sub(samples));
+    let (Some(min), Some(max)) = (hop.min_rtt, hop.max_rtt) else {
+        return " ".repeat(shown.len());
     };
+    let span = (max - min).max(1) as f64;
+    shown.map(|outcome| match outcome.rtt() {
+        Some(rtt) => BLOCKS[(((rtt.clamp(min, max) - min) as f64 / span) * 7.0).round() as usize],
+        None => ' ',
+    }).collect()
+}
 
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    ctrlc::set_handler(move || { r.store(false, Ordering::SeqCst); }).expect("Error setting Ctrl+C handler");
+/// Probe outcomes shown by the pattern display mode
+const PATTERN_SAMPLES: usize = 50;
 
-    let hops = Arc::new(Mutex::new((1..=args.max_ttl).map(HopStats::new).collect::<Vec<_>>()));
-    let target_ttl = Arc::new(Mutex::new(None::<u8>));
-    let mut last_display_count: usize = 0;
-    let mut cycle = 0u32;
+/// One character per recent probe, oldest first: `.` reply, `?` no answer, `!` ICMP error
+fn loss_pattern(hop: &HopStats, samples: usize) -> String {
+    hop.history.iter().skip(hop.history.len().saturating_sub(samples)).map(|outcome| match outcome {
+        Outcome::Reply(_) => '.',
+        Outcome::Lost => '?',
+        Outcome::Error(_) => '!',
+    }).collect()
+}
 
-    while running.load(Ordering::SeqCst) {
-        cycle += 1;
-        
-        let max_hop = target_ttl.lock().unwrap().unwrap_or(args.max_ttl);
-        
-        // Parallel probing: spawn threads for all hops
-        let mut handles = vec![];
-        for ttl in 1..=max_hop {
-            let target = target_ip;
-            let timeout = args.timeout;
-            handles.push(thread::spawn(move || probe_hop(target, ttl, timeout)));
-        }
-
-        // Collect results
-        let mut results: Vec<(u8, ProbeResult)> = vec![];
-        for handle in handles {
-            if let Ok(result) = handle.join() {
-                results.push(result);
-            }
+/// Most rows a histogram uses; 1 ms buckets are merged to fit
+const HISTOGRAM_ROWS: usize = 20;
+const HISTOGRAM_BAR: usize = 40;
+
+/// One line per RTT range from the hop's fastest reply up, with a bar scaled to the fullest range and its count
+fn histogram(hop: &HopStats) -> Vec<String> {
+    let buckets = hop.buckets();
+    let Some(first) = buckets.iter().position(|&n| n > 0) else { return vec!["  no replies".to_string()] };
+    let width = (buckets.len() - first).div_ceil(HISTOGRAM_ROWS);
+    let rows: Vec<u64> = buckets[first..].chunks(width).map(|chunk| chunk.iter().sum()).collect();
+    let peak = rows.iter().copied().max().unwrap_or(1);
+    rows.iter().enumerate().map(|(i, &count)| {
+        let low = first + i * width;
+        let bar = "#".repeat((count * HISTOGRAM_BAR as u64).div_ceil(peak) as usize);
+        format!("{:>6}-{:<6} ms |{:<w$} {}", low, low + width, bar, count, w = HISTOGRAM_BAR)
+    }).collect()
+}
+
+/// Live table layouts, cycled with `d` like mtr's display modes
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum DisplayMode {
+    /// The -o columns
+    Statistics,
+    /// Loss, last and average RTT beside the recent RTT sparkline
+    Latency,
+    /// Loss beside a strip of the last probes' outcomes
+    Pattern,
+}
+
+impl DisplayMode {
+    fn next(self) -> Self {
+        match self {
+            DisplayMode::Statistics => DisplayMode::Latency,
+            DisplayMode::Latency => DisplayMode::Pattern,
+            DisplayMode::Pattern => DisplayMode::Statistics,
         }
-        results.sort_by_key(|(ttl, _)| *ttl);
-
-        // Process results
-        {
-            let mut hops = hops.lock().unwrap();
-            let mut target_ttl = target_ttl.lock().unwrap();
-            
-            for (ttl, result) in results {
-                let hop_idx = (ttl - 1) as usize;
-                match result {
-                    ProbeResult::Reply { ip, rtt } => {
-                        hops[hop_idx].record_response(ip, rtt);
-                        if target_ttl.is_none() { *target_ttl = Some(ttl); }
-                        if !args.no_dns && hops[hop_idx].hostname.is_none() {
-                            hops[hop_idx].hostname = reverse_lookup(ip);
-                        }
-                    }
-                    ProbeResult::TtlExpired { ip, rtt } => {
-                        hops[hop_idx].record_response(ip, rtt);
-                        if !args.no_dns && hops[hop_idx].hostname.is_none() {
-                            hops[hop_idx].hostname = reverse_lookup(ip);
-                        }
-                    }
-                    ProbeResult::Unreachable { ip } => {
-                        hops[hop_idx].ip = Some(ip);
-                        hops[hop_idx].record_timeout();
-                        if !args.no_dns && hops[hop_idx].hostname.is_none() {
-                            hops[hop_idx].hostname = reverse_lookup(ip);
-                        }
-                    }
-                    ProbeResult::Timeout => {
-                        hops[hop_idx].record_timeout();
-                    }
-                }
-            }
+    }
+}
+
+/// Named column sets for --preset, cycled with `f` in the live view
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Preset {
+    /// Loss, last and average RTT
+    Minimal,
+    /// mtr's default columns
+    Classic,
+    /// Every lettered column
+    Full,
+    /// Loss and average RTT beside every jitter measure
+    Jitter,
+}
+
+impl Preset {
+    fn next(self) -> Self {
+        match self {
+            Preset::Minimal => Preset::Classic,
+            Preset::Classic => Preset::Full,
+            Preset::Full => Preset::Jitter,
+            Preset::Jitter => Preset::Minimal,
         }
+    }
 
-        // Display
-        if !args.report {
-            let display_count = {
-                let t = target_ttl.lock().unwrap();
-                t.unwrap_or(max_hop) as usize
-            };
-            let hops = hops.lock().unwrap();
-            refresh_display(&args.target, target_ip, &hops, display_count, args.no_dns, last_display_count + 2);
-            last_display_count = display_count;
+    fn fields(self) -> Fields {
+        let letters = match self {
+            Preset::Minimal => "LNA",
+            Preset::Classic => "LSNABWV",
+            Preset::Full => "LSRDNABWVMJXI",
+            Preset::Jitter => "LAMJXI",
+        };
+        parse_fields(letters).expect("preset letters are valid fields")
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Preset::Minimal => "minimal",
+            Preset::Classic => "classic",
+            Preset::Full => "full",
+            Preset::Jitter => "jitter",
         }
+    }
+}
+
+/// Ordered column selection for -o/--order
+#[derive(Clone, Debug)]
+struct Fields(Vec<Field>);
+
+/// Parse -o letters, rejecting unknown ones so a typo is not silently dropped
+fn parse_fields(spec: &str) -> Result<Fields, String> {
+    if spec.is_empty() { return Err("at least one field letter is required".to_string()); }
+    spec.chars()
+        .map(|c| Field::from_letter(c).ok_or_else(|| format!("unknown field '{}' (expected one of {})", c, Field::letters())))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Fields)
+}
+
+/// Host column width of the live table and of reports without --wide
+const HOST_WIDTH: usize = 45;
+
+/// Column header line matching format_hop for the same fields
+fn header_line(fields: &Fields, host_width: usize) -> String {
+    let mut line = format!("{:>3} {:<2$}", "", "Host", host_width);
+    for field in &fields.0 { line += &format!(" {:>1$}", field.title(), field.width()); }
+    line
+}
 
-        if args.report && cycle >= args.report_cycles { break; }
-        if args.count > 0 && cycle >= args.count { break; }
+/// Cut `text` to at most `max` characters, never splitting a multibyte character
+fn truncate_chars(text: &str, max: usize) -> &str {
+    text.char_indices().nth(max).map_or(text, |(idx, _)| &text[..idx])
+}
+
+/// Host cell text before truncation, split into the name and the ASN/location/multipath suffix
+fn host_parts(hop: &HopStats, no_dns: bool) -> (String, String) {
+    // An annotation label is the user's own name for the hop, so it shows even with --no-dns
+    let host_str = match (&hop.ip, &hop.label, &hop.hostname) {
+        (Some(ip), Some(label), _) => format!("{} ({})", label, ip),
+        (Some(ip), None, Some(hostname)) if !no_dns => format!("{} ({})", hostname, ip),
+        (Some(ip), _, _) => ip.to_string(),
+        (None, _, _) => "???".to_string(),
+    };
+    let asn = hop.asn.as_ref().map_or(String::new(), |asn| format!(" [{}]", asn));
+    let location = hop.location.as_ref().map_or(String::new(), |location| format!(" [{}]", location));
+    let extra = match hop.ips.len() { 0 | 1 => String::new(), n => format!(" (+{})", n - 1) };
+    (host_str, asn + &location + &extra)
+}
+
+/// Host column width that fits every hop untruncated, never narrower than HOST_WIDTH
+fn wide_host_width(hops: &[HopStats], no_dns: bool) -> usize {
+    hops.iter().map(|hop| {
+        let (host_str, suffix) = host_parts(hop, no_dns);
+        host_str.chars().count() + suffix.chars().count()
+    }).fold(HOST_WIDTH, usize::max)
+}
+
+/// Character after the hop number: `*` for a routing loop, `~` when the route changed this cycle
+fn hop_marker(hop: &HopStats) -> char {
+    if hop.in_loop { '*' } else if hop.route_changed { '~' } else { '.' }
+}
 
-        if running.load(Ordering::SeqCst) && args.interval > 0 {
-            std::thread::sleep(std::time::Duration::from_millis(args.interval));
+/// One table row; with `thresholds`, the Loss% and Avg cells are colored
+fn format_hop(hop: &HopStats, no_dns: bool, fields: &Fields, host_width: usize, thresholds: Option<Thresholds>) -> String {
+    // The ASN and multipath marker are kept whole; only the host part is truncated
+    let (host_str, suffix) = host_parts(hop, no_dns);
+    let width = host_width.saturating_sub(suffix.chars().count());
+    let host_str = format!("{}{}", truncate_chars(&host_str, width), suffix);
+    let mut line = format!("{:>3}{} {:<3$}", hop.ttl, hop_marker(hop), host_str, host_width);
+    for &field in &fields.0 {
+        line += " ";
+        match thresholds.and_then(|thresholds| thresholds.color(field, hop)) {
+            // Reset only the foreground, so the row's bold or reverse video carries on
+            Some(code) => line += &format!("\x1B[{}m{}\x1B[39m", code, field.cell(hop)),
+            None => line += &field.cell(hop),
         }
     }
+    line
+}
 
-    // Final report
-    println!();
-    let hops = hops.lock().unwrap();
-    let final_hops = target_ttl.lock().unwrap().unwrap_or_else(|| {
-        hops.iter().rposition(|h| h.sent > 0).map(|i| (i + 1) as u8).unwrap_or(1)
-    });
-    println!("mtr to {} ({})", args.target, target_ip);
-    println!("{:>3} {:<45} {:>6} {:>5} {:>6} {:>6} {:>6} {:>6} {:>6}", "", "Host", "Loss%", "Snt", "Last", "Avg", "Best", "Wrst", "StDev");
-    for ttl in 1..=final_hops { println!("{}", format_hop(&hops[(ttl - 1) as usize], args.no_dns)); }
-    
-    std::process::exit(0);
+/// Wrap a hop line in ANSI attributes: bold for the target row, reverse video for a hop in loss alert
+fn colorize(line: String, is_target: bool, alert: bool, color: bool) -> String {
+    let mut codes = vec![];
+    if is_target { codes.push("1"); }
+    if alert { codes.push("7"); }
+    if !color || codes.is_empty() { return line; }
+    format!("\x1B[{}m{}\x1B[0m", codes.join(";"), line)
+}
+
+/// The --overlapped engine, or a pool of `workers` threads; exits if ICMP handles cannot be opened
+fn open_prober(args: &Args, workers: usize) -> Prober {
+    if args.overlapped { return Prober::overlapped(args.timeout); }
+    Prober::new(workers, args.timeout).unwrap_or_else(|e| {
+        eprintln!("Error: Failed to create ICMP pinger: {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Size of the probe pool: --concurrency if given, else one worker per probe up to MAX_PROBE_WORKERS
+fn worker_count(concurrency: Option<u16>, probes_per_cycle: usize) -> usize {
+    concurrency.map_or(MAX_PROBE_WORKERS, usize::from).min(probes_per_cycle).max(1)
+}
+
+/// Ping every host of a CIDR block for the configured cycles and print a reachability table
+fn run_sweep(args: &Args, cidr: &str, running: &AtomicBool) {
+    let hosts = match parse_cidr(cidr) {
+        Ok(hosts) => hosts,
+        Err(e) => { eprintln!("Error: {}", e); std::process::exit(1); }
+    };
+    let mut rows: Vec<HopStats> = hosts.iter().map(|ip| {
+        let mut row = HopStats::new(0);
+        row.ip = Some(*ip);
+        row
+    }).collect();
+    // The table is printed once the sweep ends, so it cannot run unlimited like a trace
+    if args.count == Some(0) {
+        eprintln!("Error: --cidr sweeps a fixed number of cycles, so -c 0 (unlimited) does not apply");
+        std::process::exit(1);
+    }
+    let cycles = args.count.unwrap_or(args.report_cycles);
+    let prober = open_prober(args, worker_count(args.concurrency, hosts.len())).with_source(args.bind).with_packet_size(args.psize).with_bitpattern(args.bitpattern);
+    let jobs: Vec<(IpAddr, u8)> = hosts.iter().map(|&ip| (ip, args.max_ttl)).collect();
+
+    for cycle in 1..=cycles {
+        let results = prober.run(&jobs).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        });
+        for (row, result) in rows.iter_mut().zip(results) {
+            match result {
+                ProbeResult::Reply { ip, rtt } => row.record_response(ip, rtt),
+                _ => row.record_timeout(),
+            }
+        }
+        if !running.load(Ordering::SeqCst) || cycle == cycles { break; }
+        if args.interval > 0 { sleep_while_running(args.interval, running); }
+    }
+
+    if !args.no_dns {
+        for row in rows.iter_mut().filter(|r| r.received > 0) { row.hostname = row.ip.and_then(|ip| run_with_timeout(args.dns_timeout(), move || reverse_lookup(ip)).flatten()); }
+    }
+    match args.sort {
+        SweepSort::Host => {}
+        SweepSort::Loss => rows.sort_by(|a, b| a.loss_percent().total_cmp(&b.loss_percent())),
+        SweepSort::Avg => rows.sort_by(|a, b| (a.received == 0).cmp(&(b.received == 0)).then(a.avg_rtt().total_cmp(&b.avg_rtt()))),
+    }
+    for (i, row) in rows.iter_mut().enumerate() { row.ttl = (i + 1) as u8; }
+
+    let alive = rows.iter().filter(|r| r.received > 0).count();
+    println!("sweep of {} ({} of {} hosts responded)", cidr, alive, rows.len());
+    println!("{}", header_line(&args.fields, HOST_WIDTH));
+    let color = args.color.enabled();
+    let thresholds = color.then(|| args.thresholds());
+    for row in &rows { println!("{}", format_hop(row, args.no_dns, &args.fields, HOST_WIDTH, thresholds)); }
+}
+
+/// The addresses a run traces for `target`: every one with --resolve-all, else the first
+fn resolve_addresses(args: &Args, target: &str) -> Result<Vec<IpAddr>, String> {
+    if args.resolve_all {
+        resolve_all(target, args.family(), args.dns_timeout())
+    } else {
+        resolve_target(target, args.family(), args.dns_timeout()).map(|ip| vec![ip])
+    }
+}
+
+/// How the run would present its results, for --check
+fn mode_name(args: &Args) -> String {
+    if args.trace { return "trace".to_string(); }
+    if args.raw { return "raw".to_string(); }
+    if args.json_stream { return "json stream".to_string(); }
+    if args.csv_stream { return "csv stream".to_string(); }
+    if !args.report { return "live".to_string(); }
+    let format = [(args.json, "json"), (args.csv, "csv"), (args.xml, "xml"), (args.markdown, "markdown")]
+        .into_iter().find_map(|(set, name)| set.then_some(name)).unwrap_or("text");
+    format!("report ({})", format)
+}
+
+/// --check: resolve every target as a run would and print the settings it would use.
+/// Returns false when a target could not be resolved
+fn run_check(args: &Args, targets: &[String]) -> bool {
+    let mut resolved = true;
+    for target in targets {
+        match resolve_addresses(args, target) {
+            Ok(ips) => println!("{} -> {}", target, ips.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")),
+            Err(e) => { eprintln!("Error: {}", e); resolved = false; }
+        }
+    }
+    let cycles = args.cycle_limit().map_or("unlimited".to_string(), |n| n.to_string());
+    println!("interval {} ms, timeout {} ms, ttl {}-{}, cycles {}, mode {}",
+        args.interval, args.timeout, args.first_ttl, args.max_ttl, cycles, mode_name(args));
+    resolved
+}
+
+/// --trace: one probe per TTL in order, stopping at the target, printed traceroute-style.
+/// Returns false when the target could not be resolved
+fn run_trace(args: &Args, target: &str, running: &AtomicBool) -> bool {
+    let target_ip = match resolve_target(target, args.family(), args.dns_timeout()) {
+        Ok(ip) => ip,
+        Err(e) => { eprintln!("Error: {}", e); return false; }
+    };
+    let Some(mut pinger) = create_pinger() else {
+        eprintln!("Error: cannot create an ICMP pinger");
+        std::process::exit(1);
+    };
+    let mut buffer = Buffer::new();
+    let mut rng = Rng::from_clock();
+    let from = args.bind.map_or(String::new(), |source| format!(" from {}", source));
+    println!("traceroute to {} ({}){}, {} hops max", target, target_ip, from, args.max_ttl);
+    for ttl in (args.first_ttl..=args.max_ttl).filter(|&ttl| args.ttls.is_none_or(|set| set.contains(ttl))) {
+        if !running.load(Ordering::SeqCst) { break; }
+        buffer.request_data = payload(target_ip, args.psize, args.bitpattern, &mut rng);
+        let (ip, rtt, note) = match probe_hop(&mut pinger, &mut buffer, target_ip, ttl, args.timeout, args.bind) {
+            ProbeResult::Reply { ip, rtt } | ProbeResult::TtlExpired { ip, rtt } => (ip, rtt, ""),
+            ProbeResult::Unreachable { ip, rtt } => (ip, rtt, " !U"),
+            ProbeResult::Timeout => {
+                println!("{:>2}  *", ttl);
+                continue;
+            }
+        };
+        let name = if args.no_dns { None } else { run_with_timeout(args.dns_timeout(), move || reverse_lookup(ip)).flatten() };
+        let host = name.map_or(ip.to_string(), |name| format!("{} ({})", name, ip));
+        println!("{:>2}  {}  {:.3} ms{}", ttl, host, us_to_ms(rtt), note);
+        io::stdout().flush().unwrap();
+        if ip == target_ip { break; }
+    }
+    true
+}
+
+/// Inter-cycle delay after `dark_cycles` consecutive cycles without any response
+fn backoff_delay(interval: u64, dark_cycles: u32, max_backoff: u64) -> u64 {
+    let factor = 1u64.checked_shl(dark_cycles).unwrap_or(u64::MAX);
+    interval.max(1).saturating_mul(factor).min(max_backoff.max(interval))
+}
+
+/// Cycles before a still-answering hop at --max-ttl is taken to mean the path is longer
+const MAX_TTL_NOTE_CYCLES: u32 = 3;
+
+/// Multiple of the target RTT that --adaptive keeps between cycles
+const ADAPTIVE_RTT_FACTOR: u64 = 4;
+
+/// Longest delay --adaptive will choose, so one slow reply cannot freeze the display
+const ADAPTIVE_MAX_DELAY: u64 = 5_000;
+
+/// Inter-cycle delay for --adaptive: max(interval, 4 x RTT), capped at 5 s unless the interval is longer
+fn adaptive_delay(interval: u64, rtt_ms: u64) -> u64 {
+    interval.max(rtt_ms.saturating_mul(ADAPTIVE_RTT_FACTOR).min(ADAPTIVE_MAX_DELAY))
+}
+
+/// Shift `delay` by `unit` (in [-1, 1]) times `percent` of the interval, never below zero
+fn jitter_delay(delay: u64, interval: u64, percent: u8, unit: f64) -> u64 {
+    let offset = interval as f64 * f64::from(percent) / 100.0 * unit;
+    (delay as f64 + offset).round().max(0.0) as u64
+}
+
+/// Sleep for `ms` milliseconds, waking early once `running` is cleared
+fn sleep_while_running(ms: u64, running: &AtomicBool) {
+    let deadline = Instant::now() + Duration::from_millis(ms);
+    while running.load(Ordering::SeqCst) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() { break; }
+        thread::sleep(remaining.min(Duration::from_millis(100)));
+    }
+}
+
+/// Live-view commands read from the keyboard
+enum Key {
+    Pause,
+    Reset,
+    ToggleDns,
+    Display,
+    Preset,
+    /// Scroll the hop table by this many lines; Home and End saturate
+    Scroll(isize),
+    /// Scroll the hop table by this many screens
+    Page(isize),
+    /// The window changed size
+    Redraw,
+}
+
+/// Read keys in raw mode on a background thread; q and Ctrl+C stop the run like the Ctrl+C handler
+fn spawn_key_reader(running: Arc<AtomicBool>) -> io::Result<mpsc::Receiver<Key>> {
+    terminal::enable_raw_mode()?;
+    let (tx, keys) = mpsc::channel();
+    thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            let key = match event {
+                Event::Key(key) => key,
+                Event::Resize(..) => { if tx.send(Key::Redraw).is_err() { break; } continue; }
+                _ => continue,
+            };
+            if key.kind != KeyEventKind::Press { continue; }
+            let command = match key.code {
+                KeyCode::Char('p') => Key::Pause,
+                KeyCode::Char('r') => Key::Reset,
+                KeyCode::Char('n') => Key::ToggleDns,
+                KeyCode::Char('d') => Key::Display,
+                KeyCode::Char('f') => Key::Preset,
+                KeyCode::Up => Key::Scroll(-1),
+                KeyCode::Down => Key::Scroll(1),
+                KeyCode::PageUp => Key::Page(-1),
+                KeyCode::PageDown => Key::Page(1),
+                KeyCode::Home => Key::Scroll(isize::MIN),
+                KeyCode::End => Key::Scroll(isize::MAX),
+                KeyCode::Char('q') => { running.store(false, Ordering::SeqCst); continue; }
+                // Raw mode delivers Ctrl+C as a key instead of a signal
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => { running.store(false, Ordering::SeqCst); continue; }
+                _ => continue,
+            };
+            if tx.send(command).is_err() { break; }
+        }
+    });
+    Ok(keys)
+}
+
+fn key_hint(paused: bool) -> String {
+    format!("{}: p {}  r reset  n toggle DNS  d display  f preset  q quit", if paused { "Paused" } else { "Keys" }, if paused { "resume" } else { "pause" })
+}
+
+/// Full-screen live view on the alternate screen: the first table's title and column header stay
+/// at the top, a status line stays at the bottom, and the rows between scroll when they do not fit
+struct Screen {
+    started: Instant,
+    scroll: usize,
+    /// Body rows that fit in the window at the last draw, for paging
+    page: usize,
+}
+
+impl Screen {
+    fn enter() -> io::Result<Self> {
+        execute!(io::stdout(), EnterAlternateScreen, cursor::Hide)?;
+        Ok(Self { started: Instant::now(), scroll: 0, page: 1 })
+    }
+
+    fn leave(&self) {
+        let _ = execute!(io::stdout(), cursor::Show, LeaveAlternateScreen);
+    }
+
+    fn scroll_by(&mut self, lines: isize) {
+        self.scroll = self.scroll.saturating_add_signed(lines);
+    }
+
+    fn draw(&mut self, table: &[String], cycle: u32, paused: bool) -> io::Result<()> {
+        let (cols, rows) = terminal::size()?;
+        let (width, rows) = (usize::from(cols), usize::from(rows));
+        let fixed = table.len().min(2).min(rows.saturating_sub(1));
+        let (header, body) = table.split_at(fixed);
+        self.page = rows.saturating_sub(fixed + 1).max(1);
+        self.scroll = self.scroll.min(body.len().saturating_sub(self.page));
+        let shown = body.iter().skip(self.scroll).take(self.page);
+
+        let elapsed = self.started.elapsed().as_secs();
+        let mut status = format!("Cycle {}  {}:{:02}:{:02}  {}", cycle, elapsed / 3600, elapsed / 60 % 60, elapsed % 60, key_hint(paused));
+        if body.len() > self.page {
+            status.push_str(&format!("  Up/Down/PgUp/PgDn scroll, rows {}-{} of {}", self.scroll + 1, (self.scroll + self.page).min(body.len()), body.len()));
+        }
+
+        let mut out = io::stdout().lock();
+        for (row, line) in header.iter().chain(shown).enumerate() {
+            queue!(out, cursor::MoveTo(0, row as u16))?;
+            write!(out, "{}", clip(line, width))?;
+            queue!(out, Clear(ClearType::UntilNewLine))?;
+        }
+        queue!(out, Clear(ClearType::FromCursorDown), cursor::MoveTo(0, rows.saturating_sub(1) as u16))?;
+        write!(out, "{}", clip(&status, width))?;
+        out.flush()
+    }
+}
+
+/// Characters a line takes on screen, not counting ANSI escape sequences
+fn visible_width(line: &str) -> usize {
+    let mut width = 0;
+    let mut in_escape = false;
+    for c in line.chars() {
+        if in_escape { in_escape = !c.is_ascii_alphabetic(); } else if c == '\x1B' { in_escape = true; } else { width += 1; }
+    }
+    width
+}
+
+/// Cut `line` to `width` visible characters, keeping every color code so a reset is never lost
+fn clip(line: &str, width: usize) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut visible = 0;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1B' {
+            out.push(c);
+            for c in chars.by_ref() {
+                out.push(c);
+                if c.is_ascii_alphabetic() { break; }
+            }
+        } else if visible < width {
+            out.push(c);
+            visible += 1;
+        }
+    }
+    out
+}
+
+/// Hops whose loss is above --alert-loss, remembered so the bell only rings on the way up
+#[derive(Default)]
+struct LossAlerts {
+    active: HashSet<(usize, u8)>,
+}
+
+impl LossAlerts {
+    /// Track which hops are above `threshold` and report whether any crossed it this cycle
+    fn update(&mut self, traces: &[Tracer], threshold: f64) -> bool {
+        let mut crossed = false;
+        for (trace_idx, trace) in traces.iter().enumerate() {
+            for hop in trace.visible_hops() {
+                if hop.loss_percent() > threshold {
+                    crossed |= self.active.insert((trace_idx, hop.ttl));
+                } else {
+                    self.active.remove(&(trace_idx, hop.ttl));
+                }
+            }
+        }
+        crossed
+    }
+
+    fn is_active(&self, trace_idx: usize, ttl: u8) -> bool {
+        self.active.contains(&(trace_idx, ttl))
+    }
+}
+
+/// Move the cursor up over the last `lines` printed lines and erase them
+fn clear_lines(lines: usize) {
+    if lines > 0 {
+        print!("\x1B[{}A\x1B[J", lines);
+    }
+}
+
+/// --summary footer: hop count, whether the target answered, its loss and average,
+/// and the sum of per-hop averages as a rough path cost
+fn summary_line(hops: &[HopStats], target_ttl: Option<u8>) -> String {
+    let path_ms: f64 = hops.iter().filter(|h| h.received > 0).map(|h| h.avg_rtt()).sum();
+    let target = match target_ttl.and_then(|ttl| hops.iter().find(|h| h.ttl == ttl)) {
+        Some(hop) => format!("reached, loss {:.1}%, avg {:.1} ms", hop.loss_percent(), hop.avg_rtt()),
+        None => "not reached".to_string(),
+    };
+    format!("Hops: {}  Target: {}  Path sum: {:.1} ms", hops.len(), target, path_ms)
+}
+
+/// How hop tables are drawn; the live view and the final text report differ only in these
+#[derive(Clone, Copy)]
+struct TableView<'a> {
+    fields: &'a Fields,
+    no_dns: bool,
+    color: bool,
+    thresholds: Thresholds,
+    /// Fit the host column to the longest name instead of HOST_WIDTH
+    wide: bool,
+    /// List each hop's other multipath responders under its row
+    multipath: bool,
+    summary: bool,
+    /// --bind address, shown in each title
+    source: Option<IpAddr>,
+    /// Put two traces side by side instead of one under the other
+    compare: bool,
+    /// --diff-threshold: the gaps that mark a compared hop, with unmarked hops dimmed
+    diff_threshold: Option<DiffThreshold>,
+}
+
+/// Title, header, hop rows and optional footer for one trace
+fn render_trace(trace: &Tracer, hops: &[HopStats], view: TableView, alert: impl Fn(u8) -> bool) -> Vec<String> {
+    let host_width = if view.wide { wide_host_width(hops, view.no_dns) } else { HOST_WIDTH };
+    let from = view.source.map_or(String::new(), |source| format!(" from {}", source));
+    let mut lines = vec![format!("mtr to {} ({}){}", trace.target, trace.target_ip, from), header_line(view.fields, host_width)];
+    for hop in hops {
+        let line = format_hop(hop, view.no_dns, view.fields, host_width, view.color.then_some(view.thresholds));
+        lines.push(colorize(line, hop.ip == Some(trace.target_ip), alert(hop.ttl), view.color));
+        if !view.multipath { continue; }
+        for (ip, count) in hop.ips.iter().filter(|(ip, _)| Some(*ip) != hop.ip) {
+            lines.push(format!("     `-- {} ({} of {})", ip, count, hop.sent));
+        }
+    }
+    if view.summary { lines.push(summary_line(hops, trace.target_ttl)); }
+    lines
+}
+
+/// Loss gap in percentage points and average RTT gap in ms at which --compare marks a hop
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DiffThreshold {
+    loss: f64,
+    avg_ms: f64,
+}
+
+/// Gaps --compare marks when --diff-threshold is not given
+const COMPARE_GAPS: DiffThreshold = DiffThreshold { loss: 5.0, avg_ms: 20.0 };
+
+/// Parse --diff-threshold as "LOSS,RTT": percentage points, then milliseconds
+fn parse_diff_threshold(value: &str) -> Result<DiffThreshold, String> {
+    let (loss, avg_ms) = value.split_once(',').ok_or_else(|| format!("expected LOSS,RTT such as 10,50, got {}", value))?;
+    let parse = |part: &str| match part.trim().parse::<f64>() {
+        Ok(gap) if gap.is_finite() && gap >= 0.0 => Ok(gap),
+        _ => Err(format!("invalid threshold {}: expected a non-negative number", part)),
+    };
+    Ok(DiffThreshold { loss: parse(loss)?, avg_ms: parse(avg_ms)? })
+}
+
+/// Whether the same hop of two traces differs enough in loss or average RTT to point at one path
+fn diverges(a: &HopStats, b: &HopStats, gaps: DiffThreshold) -> bool {
+    if a.sent == 0 || b.sent == 0 { return false; }
+    (a.loss_percent() - b.loss_percent()).abs() >= gaps.loss
+        || (a.received > 0 && b.received > 0 && (a.avg_rtt() - b.avg_rtt()).abs() >= gaps.avg_ms)
+}
+
+/// Faint rendering of a line, dropping its own colors so none of them cancels the dimming
+fn dim(line: &str) -> String {
+    let mut plain = String::new();
+    let mut in_escape = false;
+    for c in line.chars() {
+        if in_escape { in_escape = !c.is_ascii_alphabetic(); } else if c == '\x1B' { in_escape = true; } else { plain.push(c); }
+    }
+    format!("\x1B[2m{}\x1B[0m", plain)
+}
+
+/// Two traces' tables in split columns; the gutter between them shows `≠` on hops that diverge,
+/// and with --diff-threshold in color the other hops are dimmed
+fn render_compare(left: (&Tracer, &[HopStats]), right: (&Tracer, &[HopStats]), view: TableView, alert: impl Fn(usize, u8) -> bool) -> Vec<String> {
+    // One row per hop, so row N below the title and header is hop N on both sides
+    let view = TableView { multipath: false, ..view };
+    let gaps = view.diff_threshold.unwrap_or(COMPARE_GAPS);
+    let a = render_trace(left.0, left.1, view, |ttl| alert(0, ttl));
+    let b = render_trace(right.0, right.1, view, |ttl| alert(1, ttl));
+    let width = a.iter().map(|line| visible_width(line)).max().unwrap_or(0);
+    (0..a.len().max(b.len())).map(|row| {
+        let l = a.get(row).map_or("", String::as_str);
+        let r = b.get(row).map_or("", String::as_str);
+        let hops = row.checked_sub(2).and_then(|i| left.1.get(i).zip(right.1.get(i)));
+        let marked = hops.is_some_and(|(a, b)| a.ttl == b.ttl && diverges(a, b, gaps));
+        let line = format!("{}{} {} {}", l, " ".repeat(width - visible_width(l)), if marked { '≠' } else { '|' }, r);
+        if row >= 2 && !marked && view.color && view.diff_threshold.is_some() { dim(&line) } else { line }
+    }).collect()
+}
+
+/// Lines of the live table: every target's current hops
+fn render_table(traces: &[Tracer], view: TableView, alerts: &LossAlerts) -> Vec<String> {
+    if let ([a, b], true) = (traces, view.compare) {
+        return render_compare((a, &a.visible_hops()), (b, &b.visible_hops()), view, |trace_idx, ttl| alerts.is_active(trace_idx, ttl));
+    }
+    traces.iter().enumerate()
+        .flat_map(|(trace_idx, trace)| render_trace(trace, &trace.visible_hops(), view, |ttl| alerts.is_active(trace_idx, ttl)))
+        .collect()
+}
+
+/// Redraw the live table over the previous frame and return how many lines were printed
+fn refresh_display(table: &[String], lines_to_clear: usize) -> usize {
+    // Redirected output gets each cycle's table in turn, as cursor movement would garble it
+    if !io::stdout().is_terminal() {
+        for line in table { println!("{}", line); }
+        println!();
+        return 0;
+    }
+    clear_lines(lines_to_clear);
+    // Color codes never add newlines, so the cursor-up count stays exact
+    for line in table { println!("{}", line); }
+    io::stdout().flush().unwrap();
+    table.len()
+}
+
+/// Name the active preset in the first title, which the full-screen view keeps at the top
+fn label_preset(table: &mut [String], preset: Option<Preset>) {
+    if let (Some(title), Some(preset)) = (table.first_mut(), preset) { title.push_str(&format!("  [preset: {}]", preset.name())); }
+}
+
+/// Draw the live table full-screen when there is a Screen, else redraw it in place;
+/// returns the lines to clear before the next in-place redraw
+fn show_live(table: &[String], screen: &mut Option<Screen>, cycle: u32, paused: bool, lines_to_clear: usize) -> usize {
+    if let Some(screen) = screen {
+        if screen.draw(table, cycle, paused).is_ok() { return 0; }
+    }
+    refresh_display(table, lines_to_clear)
+}
+
+/// Log notes for hops whose primary (most frequent) responder switched during the latest cycle
+fn route_change_notes(traces: &[Tracer]) -> Vec<String> {
+    traces.iter().flat_map(|trace| {
+        trace.visible_hops().into_iter().filter(|hop| hop.route_changed).filter_map(|hop| {
+            let change = RouteChangeReport::from(hop.route_changes.back()?);
+            Some(format!("route change {} hop {}: {} -> {} at {}", trace.target, hop.ttl, change.from, change.to, change.time))
+        }).collect::<Vec<_>>()
+    }).collect()
+}
+
+/// Append one cycle's table to the --log-file, uncolored and under a timestamp header
+fn log_cycle(log: &mut File, cycle: u32, table: &[String]) -> io::Result<()> {
+    writeln!(log, "--- {} cycle {} ---", Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true), cycle)?;
+    for line in table { writeln!(log, "{}", line)?; }
+    writeln!(log)?;
+    log.flush()
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || { r.store(false, Ordering::SeqCst); }).expect("Error setting Ctrl+C handler");
+
+    // Without an ICMP handle every probe would just time out and look like total loss
+    if let Err(e) = check_pinger() {
+        eprintln!("Error: Failed to create ICMP pinger - try running as Administrator: {}", e);
+        std::process::exit(1);
+    }
+
+    // Binding a socket only succeeds for an address assigned to this machine
+    if let Some(source) = args.bind {
+        if let Err(e) = UdpSocket::bind((source, 0)) {
+            eprintln!("Error: --bind {} is not a local address: {}", source, e);
+            std::process::exit(1);
+        }
+    }
+
+    if args.warn_loss > args.crit_loss || args.warn_latency > args.crit_latency {
+        eprintln!("Error: --warn-loss and --warn-latency must not exceed --crit-loss and --crit-latency");
+        std::process::exit(1);
+    }
+
+    if let Some(cidr) = &args.cidr {
+        run_sweep(&args, cidr, &running);
+        std::process::exit(0);
+    }
+
+    if args.first_ttl > args.max_ttl {
+        eprintln!("Error: --first-ttl {} is beyond --max-ttl {}", args.first_ttl, args.max_ttl);
+        std::process::exit(1);
+    }
+    if let Some(lowest) = args.ttls.and_then(|ttls| ttls.min()).filter(|&ttl| ttl < args.first_ttl) {
+        eprintln!("Error: --ttls includes {}, below --first-ttl {}", lowest, args.first_ttl);
+        std::process::exit(1);
+    }
+    if let Some(highest) = args.ttls.and_then(|ttls| ttls.max()).filter(|&ttl| ttl > args.max_ttl) {
+        eprintln!("Error: --ttls includes {}, beyond --max-ttl {}", highest, args.max_ttl);
+        std::process::exit(1);
+    }
+    let mut targets = args.targets.clone();
+    if let Some(path) = &args.targets_file {
+        match std::fs::read_to_string(path) {
+            Ok(text) => targets.extend(parse_targets(&text)),
+            Err(e) => { eprintln!("Error: cannot read {}: {}", path, e); std::process::exit(1); }
+        }
+    }
+    if args.compare && targets.len() != 2 {
+        eprintln!("Error: --compare takes exactly two targets");
+        std::process::exit(1);
+    }
+    if args.json && args.json_format == JsonFormat::Mtr && (targets.len() > 1 || args.resolve_all) {
+        eprintln!("Error: --json-format mtr takes a single target, as mtr's JSON document describes one trace");
+        std::process::exit(1);
+    }
+    if args.xml && (targets.len() > 1 || args.resolve_all) {
+        eprintln!("Error: --xml takes a single target, as mtr's XML document describes one trace");
+        std::process::exit(1);
+    }
+    if args.raw && (targets.len() > 1 || args.resolve_all) {
+        eprintln!("Error: --raw takes a single target, as its records carry no target field");
+        std::process::exit(1);
+    }
+    if args.check {
+        if targets.is_empty() { eprintln!("Error: no targets given"); }
+        std::process::exit(if !targets.is_empty() && run_check(&args, &targets) { 0 } else { 1 });
+    }
+    if args.trace {
+        let mut traced = false;
+        for target in &targets { traced |= run_trace(&args, target, &running); }
+        std::process::exit(if traced { 0 } else { 1 });
+    }
+    let shows_table = !args.report && !args.csv_stream && !args.json_stream && !args.raw;
+    let config = TraceConfig {
+        first_ttl: args.first_ttl,
+        max_ttl: args.max_ttl,
+        error_policy: args.count_errors_as,
+        sample_cap: if args.percentiles { args.sample_window as usize } else { 0 },
+        window: args.window.map_or(0, |n| n as usize),
+        // --recent, --sparkline and --html read the same per-hop history, sized for the longest
+        history: [
+            args.recent.map_or(0, usize::from),
+            args.sparkline.map_or(0, usize::from),
+            // The live table's latency and pattern modes draw from it even without those flags
+            if shows_table { SPARKLINE_SAMPLES.max(PATTERN_SAMPLES) } else { 0 },
+            if args.html.is_some() { html::CHART_SAMPLES } else { 0 },
+        ].into_iter().max().unwrap_or(0),
+        buckets: args.histogram.is_some(),
+        max_unknown: args.max_unknown,
+        ttls: args.ttls,
+    };
+    // --recent and --sparkline columns follow whichever statistics columns are showing
+    let extras: Vec<Field> = [args.recent.map(|n| Field::Recent(n.into())), args.sparkline.map(|n| Field::Sparkline(n.into()))]
+        .into_iter().flatten().collect();
+    let with_extras = |mut fields: Fields| {
+        fields.0.extend(extras.iter().copied());
+        fields
+    };
+    let fields = with_extras(args.fields.clone());
+    let preset_fields: Vec<Fields> = Preset::value_variants().iter().map(|preset| with_extras(preset.fields())).collect();
+    // A target that fails to resolve is reported and skipped so the rest still run
+    let addresses: Vec<(&String, IpAddr)> = targets.iter().flat_map(|target| {
+        match resolve_addresses(&args, target) {
+            Ok(ips) => ips.into_iter().map(|ip| (target, ip)).collect(),
+            Err(e) => { eprintln!("Error: {}", e); Vec::new() }
+        }
+    }).collect();
+    if addresses.is_empty() {
+        if targets.is_empty() { eprintln!("Error: no targets given"); }
+        std::process::exit(1);
+    }
+
+    let slots = addresses.len() * (args.max_ttl - args.first_ttl + 1) as usize;
+    let prober = open_prober(&args, worker_count(args.concurrency, slots)).with_retries(args.retries).with_source(args.bind).with_packet_size(args.psize).with_bitpattern(args.bitpattern);
+    // A missing or unreadable cache just means starting without names
+    let cached_names = args.dns_cache.as_ref()
+        .map_or(Vec::new(), |path| std::fs::read_to_string(path).map(|text| parse_dns_cache(&text)).unwrap_or_default());
+    let ptr_resolver = || {
+        let dns = DnsResolver::new(args.dns_timeout());
+        dns.preload(cached_names.iter().cloned());
+        dns
+    };
+    // The reverse DNS resolver, when there is one, always comes first
+    let mut resolvers: Vec<DnsResolver> = [(!args.no_dns).then(ptr_resolver), args.asn.then(DnsResolver::asn)]
+        .into_iter().flatten().collect();
+    if let Some(path) = &args.annotations {
+        match std::fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|text| parse_annotations(&text)) {
+            Ok(annotations) => resolvers.push(DnsResolver::label(move |ip| annotation_for(&annotations, ip))),
+            Err(e) => {
+                eprintln!("Error: {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    #[cfg(feature = "geoip")]
+    if let Some(path) = &args.geoip {
+        match geoip::open(path) {
+            Ok(lookup) => resolvers.push(DnsResolver::location(lookup)),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut session = MtrSession::new(config, prober).with_resolvers(resolvers);
+    for (target, ip) in addresses { session.add_address(target, ip); }
+    let color = args.color.enabled();
+    let mut lines_on_screen: usize = 0;
+    let tag = args.tag.as_deref();
+    let tag_column = if tag.is_some() { "tag," } else { "" };
+    if args.csv_stream { print!("{}{}{}", if args.timestamp { "timestamp," } else { "" }, tag_column, CSV_STREAM_HEADER); }
+    #[cfg(feature = "prometheus")]
+    let exposition = args.prometheus_port.map(|port| metrics::serve(port).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }));
+    let mut raw = args.raw.then(RawWriter::default);
+    let mut log = args.log_file.as_ref().map(|path| match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => (path, file),
+        Err(e) => { eprintln!("Error: cannot open {}: {}", path, e); std::process::exit(1); }
+    });
+    // The header goes in only once, so resuming a capture keeps one importable table
+    let mut csv_file = args.csv_file.as_ref().map(|path| {
+        let opened = OpenOptions::new().create(true).append(true).open(path).and_then(|mut file| {
+            if file.metadata()?.len() == 0 { write!(file, "timestamp,{}{}", tag_column, CSV_STREAM_HEADER)?; }
+            Ok(file)
+        });
+        match opened {
+            Ok(file) => (path, file),
+            Err(e) => { eprintln!("Error: cannot open {}: {}", path, e); std::process::exit(1); }
+        }
+    });
+    let mut alerts = LossAlerts::default();
+    let mut cycle = 0u32;
+    let cycle_limit = args.cycle_limit();
+    let mut rng = args.seed.map_or_else(Rng::from_clock, Rng::new);
+    let deadline = args.deadline.map(|secs| Instant::now() + Duration::from_secs(secs));
+    let past_deadline = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+    let mut dark_cycles = 0u32;
+    let live = shows_table && io::stdin().is_terminal() && io::stdout().is_terminal();
+    let mut max_ttl_noted: HashSet<usize> = HashSet::new();
+    let mut notes: Vec<String> = Vec::new();
+    let keys = live.then(|| spawn_key_reader(running.clone()).ok()).flatten();
+    let mut screen = keys.is_some().then(|| Screen::enter().ok()).flatten();
+    let mut view = TableView { fields: &fields, no_dns: args.no_dns, color, thresholds: args.thresholds(), wide: false, multipath: false, summary: args.summary, source: args.bind, compare: args.compare, diff_threshold: args.diff_threshold };
+    let mut ptr_lookups = !args.no_dns;
+    let mut paused = false;
+    let latency_fields = Fields(vec![Field::Loss, Field::Last, Field::Avg, Field::Sparkline(SPARKLINE_SAMPLES)]);
+    let pattern_fields = Fields(vec![Field::Loss, Field::Pattern(PATTERN_SAMPLES)]);
+    let fields_for = |display, preset: Option<Preset>| match display {
+        DisplayMode::Statistics => preset.map_or(&fields, |preset| &preset_fields[preset as usize]),
+        DisplayMode::Latency => &latency_fields,
+        DisplayMode::Pattern => &pattern_fields,
+    };
+    let mut display = args.display;
+    let mut preset = args.preset;
+    view.fields = fields_for(display, preset);
+
+    while running.load(Ordering::SeqCst) && !past_deadline() {
+        let mut redraw = false;
+        for key in keys.iter().flat_map(mpsc::Receiver::try_iter) {
+            match key {
+                Key::Pause => paused = !paused,
+                Key::Reset => {
+                    session.reset();
+                    alerts = LossAlerts::default();
+                }
+                Key::ToggleDns => {
+                    view.no_dns = !view.no_dns;
+                    // A run started with -n has no reverse lookups going yet
+                    if !ptr_lookups {
+                        session.resolvers.insert(0, ptr_resolver());
+                        ptr_lookups = true;
+                    }
+                }
+                Key::Display => {
+                    display = display.next();
+                    view.fields = fields_for(display, preset);
+                }
+                Key::Preset => {
+                    // Presets are statistics columns, so cycling them also leaves the latency and pattern modes
+                    preset = Some(preset.map_or(Preset::Minimal, Preset::next));
+                    display = DisplayMode::Statistics;
+                    view.fields = fields_for(display, preset);
+                }
+                Key::Scroll(lines) => if let Some(screen) = &mut screen { screen.scroll_by(lines); },
+                Key::Page(pages) => if let Some(screen) = &mut screen { screen.scroll_by(pages * screen.page as isize); },
+                Key::Redraw => {}
+            }
+            redraw = true;
+        }
+        if redraw {
+            let mut table = render_table(&session.traces, view, &alerts);
+            label_preset(&mut table, preset.filter(|_| display == DisplayMode::Statistics));
+            table.extend(notes.iter().cloned());
+            if screen.is_none() { table.push(key_hint(paused)); }
+            lines_on_screen = show_live(&table, &mut screen, cycle, paused, lines_on_screen);
+        }
+        if paused {
+            sleep_while_running(100, &running);
+            continue;
+        }
+        cycle += 1;
+
+        // Parallel probing: one job per target and hop on the shared worker pool
+        let probes = match session.run_cycle() {
+            Ok(probes) => probes,
+            Err(e) => {
+                if let Some(screen) = &screen { screen.leave(); }
+                if keys.is_some() { let _ = terminal::disable_raw_mode(); }
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let dark = probes.iter().all(|(_, r)| matches!(r, ProbeResult::Timeout));
+
+        if let Some(raw) = &mut raw {
+            let mut out = io::stdout().lock();
+            for ((_, ttl), result) in &probes { write!(out, "{}", raw.probe(*ttl, result, args.count_errors_as)).unwrap(); }
+        }
+        // A path longer than --max-ttl looks like a dead end, so say once per target which it is
+        if cycle >= MAX_TTL_NOTE_CYCLES {
+            for (trace_idx, trace) in session.traces.iter().enumerate() {
+                if !trace.beyond_max_ttl() || !max_ttl_noted.insert(trace_idx) { continue; }
+                let note = format!("Note: {} not reached within max-ttl {}; try increasing -m", trace.target, args.max_ttl);
+                if shows_table { notes.push(note); } else { eprintln!("{}", note); }
+            }
+        }
+
+        // Display
+        if let Some(raw) = &mut raw {
+            print!("{}", raw.names(&session.traces[0].visible_hops()));
+            io::stdout().flush().unwrap();
+        } else if args.json_stream {
+            // serde_json's compact form never contains a newline, so each cycle is one line
+            let line = serde_json::to_string(&CycleEvent::new(cycle, &session.traces, args.bind, tag)).expect("cycle serializes to JSON");
+            let mut out = io::stdout().lock();
+            writeln!(out, "{}", line).unwrap();
+            out.flush().unwrap();
+        } else if args.csv_stream {
+            let ts = args.timestamp.then(|| Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true));
+            let mut out = io::stdout().lock();
+            for trace in &session.traces { write!(out, "{}", csv_stream_rows(trace, cycle, ts.as_deref(), tag)).unwrap(); }
+            out.flush().unwrap();
+        } else if !args.report {
+            if let Some(threshold) = args.alert_loss {
+                if alerts.update(&session.traces, threshold) && io::stdout().is_terminal() { print!("\x07"); }
+            }
+            let mut table = render_table(&session.traces, view, &alerts);
+            label_preset(&mut table, preset.filter(|_| display == DisplayMode::Statistics));
+            table.extend(notes.iter().cloned());
+            if keys.is_some() && screen.is_none() { table.push(key_hint(paused)); }
+            lines_on_screen = show_live(&table, &mut screen, cycle, paused, lines_on_screen);
+        }
+        #[cfg(feature = "prometheus")]
+        if let Some(exposition) = &exposition { *exposition.lock().unwrap() = metrics::render(&session.traces, tag); }
+        if let Some((path, file)) = &mut csv_file {
+            let ts = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+            let rows: String = session.traces.iter().map(|trace| csv_stream_rows(trace, cycle, Some(&ts), tag)).collect();
+            if let Err(e) = file.write_all(rows.as_bytes()).and_then(|_| file.flush()) {
+                eprintln!("Error: cannot write {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        if let Some((path, log)) = &mut log {
+            let mut table = render_table(&session.traces, TableView { color: false, ..view }, &alerts);
+            table.extend(route_change_notes(&session.traces));
+            if let Err(e) = log_cycle(log, cycle, &table) {
+                eprintln!("Error: cannot write {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+
+        if cycle_limit.is_some_and(|limit| cycle >= limit) { break; }
+
+        let mut delay = if args.backoff && dark {
+            dark_cycles += 1;
+            backoff_delay(args.interval, dark_cycles, args.max_backoff)
+        } else {
+            dark_cycles = 0;
+            args.interval
+        };
+        if args.adaptive {
+            // The slowest target this cycle sets the pace; unanswered cycles are left to --backoff
+            let rtt_us = session.traces.iter().filter_map(Tracer::target_rtt).max().unwrap_or(0);
+            delay = delay.max(adaptive_delay(args.interval, (rtt_us / 1000) as u64));
+        }
+        if let Some(percent) = args.interval_jitter {
+            delay = jitter_delay(delay, args.interval, percent, rng.next_unit());
+        }
+        // Cut the last sleep short so a long interval cannot overshoot the deadline
+        if let Some(deadline) = deadline {
+            delay = delay.min(deadline.saturating_duration_since(Instant::now()).as_millis() as u64);
+        }
+        if delay > 0 { sleep_while_running(delay, &running); }
+    }
+    if let Some(screen) = &screen { screen.leave(); }
+    if keys.is_some() { let _ = terminal::disable_raw_mode(); }
+
+    let save_dns_cache = || {
+        let Some(path) = args.dns_cache.as_ref().filter(|_| ptr_lookups) else { return };
+        if let Err(e) = write_dns_cache(path, &mut session.resolvers[0].names()) { eprintln!("Warning: cannot write {}: {}", path, e); }
+    };
+
+    let write_html = || {
+        let Some(path) = &args.html else { return };
+        let generated = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        if let Err(e) = std::fs::write(path, html::render(&session.traces, &generated)) {
+            eprintln!("Error: cannot write {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+
+    // The stream already carries every cycle, so there is no final report
+    if args.csv_stream || args.json_stream || args.raw {
+        save_dns_cache();
+        write_html();
+        std::process::exit(0);
+    }
+
+    // Final report
+    for dns in &session.resolvers { dns.drain(DNS_DRAIN_TIMEOUT); }
+    save_dns_cache();
+    write_html();
+    // Erase the live table so the summary replaces it instead of stacking below it
+    if lines_on_screen > 0 {
+        clear_lines(lines_on_screen);
+        io::stdout().flush().unwrap();
+    }
+    let sections: Vec<(&Tracer, Vec<HopStats>)> = session.traces.iter().map(|trace| (trace, trace.visible_hops())).collect();
+    for (trace, hops) in &sections {
+        let looped: Vec<String> = hops.iter().filter(|h| h.in_loop).map(|h| h.ttl.to_string()).collect();
+        if trace.target_ttl.is_none() && !looped.is_empty() {
+            eprintln!("Warning: {} was not reached; possible routing loop at TTLs {}", trace.target, looped.join(", "));
+        }
+    }
+    let exit_code = args.loss_threshold.map_or(0, |threshold| {
+        session.traces.iter().map(|trace| match trace.target_loss() {
+            None => 2,
+            Some(loss) if loss >= threshold => 1,
+            Some(_) => 0,
+        }).max().unwrap_or(0)
+    });
+    let reports = || sections.iter().map(|(trace, hops)| Report::new(&trace.target, trace.target_ip, args.bind, hops).tagged(tag));
+    if args.json {
+        // The shape never depends on how many targets there are; mtr's layout is limited to one
+        let json = match args.json_format {
+            JsonFormat::Winmtr => serde_json::to_string_pretty(&JsonReports { traces: reports().collect() }),
+            JsonFormat::Mtr => {
+                let report = reports().next().expect("at least one trace ran");
+                serde_json::to_string_pretty(&report.to_mtr_json(cycle, args.psize, args.bitpattern))
+            }
+        };
+        println!("{}", json.expect("report serializes to JSON"));
+        std::process::exit(exit_code);
+    }
+    if args.xml {
+        for report in reports() { print!("{}", report.to_xml(cycle, args.psize, args.bitpattern)); }
+        std::process::exit(exit_code);
+    }
+    if args.csv {
+        print!("{}{}", tag_column, CSV_HEADER);
+        for report in reports() { print!("{}", report.csv_rows()); }
+        std::process::exit(exit_code);
+    }
+    if args.markdown {
+        let tables: Vec<String> = reports().map(|report| report.to_markdown()).collect();
+        print!("{}", tables.join("\n"));
+        std::process::exit(exit_code);
+    }
+    // The report keeps the columns of the preset last chosen with `f`
+    let mut report_fields = fields_for(DisplayMode::Statistics, preset).clone();
+    if args.ci { report_fields.0.push(Field::Ci95); }
+    if args.percentiles { report_fields.0.extend([Field::P50, Field::P90, Field::P95, Field::P99]); }
+    if sections.iter().any(|(_, hops)| hops.iter().any(|h| h.route_change_count > 0)) { report_fields.0.push(Field::Changes); }
+    // Keeps the DNS display the user last chose with `n`
+    let view = TableView { fields: &report_fields, wide: args.wide, multipath: true, ..view };
+    let compared = match &sections[..] {
+        [(a, a_hops), (b, b_hops)] if view.compare => Some(render_compare((a, a_hops), (b, b_hops), view, |_, _| false)),
+        _ => None,
+    };
+    if let Some(lines) = &compared {
+        println!();
+        for line in lines { println!("{}", line); }
+    }
+    for (trace, hops) in &sections {
+        if compared.is_none() {
+            println!();
+            for line in render_trace(trace, hops, view, |_| false) { println!("{}", line); }
+        }
+        if let Some(ttl) = args.histogram {
+            let Some(hop) = ttl.map_or(hops.last(), |ttl| hops.iter().find(|hop| hop.ttl == ttl)) else {
+                println!("\nNo such hop to draw a histogram for");
+                continue;
+            };
+            println!("\nRTT histogram for hop {} ({})", hop.ttl, hop.ip.map_or("???".to_string(), |ip| ip.to_string()));
+            for line in histogram(hop) { println!("{}", line); }
+        }
+    }
+
+    std::process::exit(exit_code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOP_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    #[test]
+    fn targets_file_skips_blank_lines_and_comments() {
+        let text = "# monitored endpoints\n8.8.8.8\n\n  example.com  # web\n\t\n1.1.1.1\n";
+        assert_eq!(parse_targets(text), vec!["8.8.8.8", "example.com", "1.1.1.1"]);
+    }
+
+    #[test]
+    fn summary_sums_hop_averages_and_reports_the_target() {
+        let target = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        let mut hops = vec![HopStats::new(1), HopStats::new(2), HopStats::new(3)];
+        hops[0].record_response(HOP_IP, 2000);
+        hops[1].record_timeout();
+        hops[2].record_response(target, 10_000);
+        hops[2].record_timeout();
+        assert_eq!(summary_line(&hops, Some(3)), "Hops: 3  Target: reached, loss 50.0%, avg 10.0 ms  Path sum: 12.0 ms");
+        assert_eq!(summary_line(&hops[..2], None), "Hops: 2  Target: not reached  Path sum: 2.0 ms");
+    }
+
+    #[test]
+    fn annotations_pick_the_most_specific_match() {
+        let text = "# internal routers\n[annotations]\n\"10.0.0.0/8\" = \"corp-core\"\n\"10.1.2.3\" = \"office-edge-fw\"  # PTR is useless\n\"2001:db8::/32\" = \"lab v6\"\n";
+        let annotations = parse_annotations(text).unwrap();
+        let label = |ip: &str| annotation_for(&annotations, ip.parse().unwrap());
+        assert_eq!(label("10.1.2.3").as_deref(), Some("office-edge-fw"));
+        assert_eq!(label("10.200.0.1").as_deref(), Some("corp-core"));
+        assert_eq!(label("2001:db8::1").as_deref(), Some("lab v6"));
+        assert_eq!(label("192.0.2.1"), None);
+        assert_eq!(parse_annotations("[annotations]\n\"10.0.0.0/33\" = \"x\"").unwrap_err(), "annotation \"10.0.0.0/33\": invalid prefix /33");
+        assert!(parse_annotations("10.0.0.1 office").is_err());
+    }
+
+    #[test]
+    fn dns_cache_file_skips_malformed_lines() {
+        let text = "192.0.2.1 router.example.net\nnot-an-ip host\n\n2001:db8::1 v6.example.net\n198.51.100.7 two names\n";
+        let names = parse_dns_cache(text);
+        assert_eq!(names, vec![
+            (HOP_IP, "router.example.net".to_string()),
+            ("2001:db8::1".parse().unwrap(), "v6.example.net".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn ttls_accept_lists_and_ranges() {
+        let ttls = parse_ttls("1,5,8-10").unwrap();
+        let set: Vec<u8> = (1..=12).filter(|&ttl| ttls.contains(ttl)).collect();
+        assert_eq!(set, vec![1, 5, 8, 9, 10]);
+        assert_eq!((ttls.min(), ttls.max()), (Some(1), Some(10)));
+        assert!(parse_ttls("0").is_err());
+        assert!(parse_ttls("12-8").is_err());
+        assert!(parse_ttls("3,x").is_err());
+    }
+
+    #[test]
+    fn explicit_count_takes_precedence_in_report_mode() {
+        let limit = |argv: &[&str]| Args::try_parse_from(argv).unwrap().cycle_limit();
+        assert_eq!(limit(&["mtr", "-r", "-c", "5", "example.com"]), Some(5));
+        assert_eq!(limit(&["mtr", "-r", "-C", "20", "example.com"]), Some(20));
+        assert_eq!(limit(&["mtr", "-r", "example.com"]), Some(10));
+        assert_eq!(limit(&["mtr", "-r", "-C", "20", "-c", "3", "example.com"]), Some(3));
+        assert_eq!(limit(&["mtr", "-c", "5", "example.com"]), Some(5));
+        assert_eq!(limit(&["mtr", "example.com"]), None);
+    }
+
+    #[test]
+    fn check_names_the_mode_a_run_would_use() {
+        let mode = |argv: &[&str]| mode_name(&Args::try_parse_from(argv).unwrap());
+        assert_eq!(mode(&["mtr", "example.com"]), "live");
+        assert_eq!(mode(&["mtr", "-r", "--json", "example.com"]), "report (json)");
+        assert_eq!(mode(&["mtr", "-r", "example.com"]), "report (text)");
+        assert_eq!(mode(&["mtr", "--csv-stream", "example.com"]), "csv stream");
+        assert_eq!(mode(&["mtr", "--trace", "example.com"]), "trace");
+    }
+
+    #[test]
+    fn mtr_json_uses_mtr_key_names() {
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 1500);
+        let report = Report::new("example", HOP_IP, None, &[hop, HopStats::new(2)]).to_mtr_json(1, 0, 0);
+        let json = serde_json::to_value(report).unwrap();
+        assert_eq!(json["report"]["mtr"]["dst"], "example");
+        assert_eq!(json["report"]["mtr"]["tests"], 1);
+        let hubs = json["report"]["hubs"].as_array().unwrap();
+        assert_eq!(hubs[0]["count"], 1);
+        assert_eq!(hubs[0]["Avg"], 1.5);
+        assert_eq!(hubs[0]["Loss%"], 0.0);
+        assert_eq!(hubs[1]["host"], "???");
+        assert_eq!(hubs[1]["Best"], 0.0);
+    }
+
+    #[test]
+    fn xml_report_matches_mtr_layout() {
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 2000);
+        hop.hostname = Some("a&b.example".to_string());
+        let xml = Report::new("example", HOP_IP, None, &[hop]).to_xml(1, 0, 0);
+        let lines: Vec<&str> = xml.lines().collect();
+        assert_eq!(lines[0], "<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+        assert!(lines[1].starts_with("<MTR SRC=") && lines[1].ends_with("DST=\"example\" TOS=\"0x0\" PSIZE=\"28\" BITPATTERN=\"0x00\" TESTS=\"1\">"));
+        assert_eq!(lines[2], "    <HUB COUNT=\"1\" HOST=\"a&amp;b.example\">");
+        assert_eq!(lines[3], "        <Loss%>0.0%</Loss%>");
+        assert_eq!(lines[5], "        <Last>2.00</Last>");
+        assert_eq!(lines.last(), Some(&"</MTR>"));
+    }
+
+    #[test]
+    fn json_report_is_an_array_even_for_one_target() {
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 1500);
+        let json = serde_json::to_value(JsonReports { traces: vec![Report::new("example", HOP_IP, None, &[hop])] }).unwrap();
+        let traces = json["traces"].as_array().unwrap();
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0]["target"], "example");
+        assert_eq!(traces[0]["hops"][0]["ttl"], 1);
+    }
+
+    #[test]
+    fn tag_is_carried_by_json_and_csv_reports() {
+        let report = Report::new("example", HOP_IP, None, &[HopStats::new(1)]).tagged(Some("site \"a\""));
+        assert_eq!(serde_json::to_value(&report).unwrap()["tag"], "site \"a\"");
+        assert!(report.csv_rows().starts_with("\"site \"\"a\"\"\",\"example\","));
+        assert!(parse_tag(&"x".repeat(MAX_TAG_LEN)).is_ok());
+        assert!(parse_tag(&"x".repeat(MAX_TAG_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn random_bitpattern_is_not_reported_as_a_fixed_byte() {
+        let report = Report::new("example", HOP_IP, None, &[HopStats::new(1)]);
+        assert!(report.to_xml(1, 0, -1).contains(" BITPATTERN=\"rand(0x00-FF)\" "));
+        assert!(report.to_xml(1, 0, 0xA5).contains(" BITPATTERN=\"0xA5\" "));
+        let json = serde_json::to_value(report.to_mtr_json(1, 0, -1)).unwrap();
+        assert_eq!(json["report"]["mtr"]["bitpattern"], "rand(0x00-FF)");
+    }
+
+    #[test]
+    fn markdown_rows_escape_pipes() {
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 2000);
+        hop.hostname = Some("core|edge.example".to_string());
+        let table = Report::new("example", HOP_IP, None, &[hop, HopStats::new(2)]).to_markdown();
+        let rows: Vec<&str> = table.lines().collect();
+        assert_eq!(rows[0], "**mtr to example (192.0.2.1)**");
+        assert_eq!(rows[4], "| 1 | core\\|edge.example (192.0.2.1) | 0.0% | 1 | 2.0 | 2.0 | 2.0 | 2.0 | --- | --- |");
+        assert_eq!(rows[5], "| 2 | ??? | 0.0% | 0 | --- | --- | --- | --- | --- | --- |");
+    }
+
+    #[test]
+    fn interval_jitter_stays_in_bounds_and_follows_the_seed() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        for _ in 0..1000 {
+            let unit = a.next_unit();
+            assert_eq!(unit, b.next_unit());
+            assert!((-1.0..1.0).contains(&unit));
+            assert!((800..=1200).contains(&jitter_delay(1000, 1000, 20, unit)));
+        }
+        assert_eq!(jitter_delay(1000, 1000, 20, -1.0), 800);
+        assert_eq!(jitter_delay(100, 1000, 50, -1.0), 0);
+    }
+
+    #[test]
+    fn adaptive_delay_follows_slow_rtts_up_to_the_cap() {
+        assert_eq!(adaptive_delay(500, 20), 500);
+        assert_eq!(adaptive_delay(500, 300), 1200);
+        assert_eq!(adaptive_delay(500, 4000), ADAPTIVE_MAX_DELAY);
+        assert_eq!(adaptive_delay(10_000, 4000), 10_000);
+    }
+
+    #[test]
+    fn raw_records_follow_mtr_tokens() {
+        let mut raw = RawWriter::default();
+        let reply = ProbeResult::TtlExpired { ip: HOP_IP, rtt: 1234 };
+        assert_eq!(raw.probe(1, &reply, ErrorPolicy::Lost), "x 0 1\nh 0 192.0.2.1\np 0 1234 1\n");
+        // The host line is only repeated when the responder changes
+        assert_eq!(raw.probe(1, &reply, ErrorPolicy::Lost), "x 0 2\np 0 1234 2\n");
+        assert_eq!(raw.probe(2, &ProbeResult::Timeout, ErrorPolicy::Lost), "x 1 3\n");
+    }
+
+    #[test]
+    fn durations_accept_milliseconds_or_decimal_seconds() {
+        assert_eq!(parse_millis("250"), Ok(250));
+        assert_eq!(parse_millis("0.25"), Ok(250));
+        assert_eq!(parse_millis("1.0"), Ok(1000));
+        assert_eq!(parse_millis(".2"), Ok(200));
+        assert!(parse_millis("-0.5").is_err());
+        assert!(parse_millis("fast").is_err());
+        assert_eq!(parse_timeout("1.5"), Ok(1500));
+        assert!(parse_timeout("5000000.0").is_err());
+    }
+
+    #[test]
+    fn order_selects_and_reorders_columns() {
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 12_000);
+        hop.record_timeout();
+        let fields = parse_fields("AL").unwrap();
+        assert_eq!(format_hop(&hop, true, &fields, HOST_WIDTH, None), format!("  1. {:<45}   12.0  50.0%", "192.0.2.1"));
+        assert_eq!(header_line(&fields, HOST_WIDTH), format!("{:>3} {:<45}    Avg  Loss%", "", "Host"));
+        assert!(parse_fields("LSQ").unwrap_err().contains("'Q'"));
+        assert!(parse_fields("Q").unwrap_err().contains("J L M N R S V W X"));
+        assert!(parse_fields("").is_err());
+    }
+
+    #[test]
+    fn live_and_report_tables_share_rows() {
+        let trace = Tracer::new("example", HOP_IP, TraceConfig {
+            first_ttl: 1, max_ttl: 2, error_policy: ErrorPolicy::Lost, sample_cap: 0, window: 0, history: 0, buckets: false, max_unknown: 0, ttls: None,
+        });
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 1000);
+        hop.record_response(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 9)), 1000);
+        let fields = parse_fields("L").unwrap();
+        let live = TableView { fields: &fields, no_dns: true, color: false, thresholds: THRESHOLDS, wide: false, multipath: false, summary: false, source: None, compare: false, diff_threshold: None };
+        let report = TableView { multipath: true, ..live };
+        let live_lines = render_trace(&trace, std::slice::from_ref(&hop), live, |_| false);
+        let report_lines = render_trace(&trace, std::slice::from_ref(&hop), report, |_| false);
+        assert_eq!(live_lines.len(), 3);
+        assert_eq!(report_lines[..3], live_lines[..]);
+        assert_eq!(report_lines[3], "     `-- 192.0.2.9 (1 of 2)");
+    }
+
+    #[test]
+    fn presets_cycle_back_to_the_first_and_name_the_title() {
+        let mut preset = Preset::Minimal;
+        for _ in 0..Preset::value_variants().len() { preset = preset.next(); }
+        assert_eq!(preset, Preset::Minimal);
+        assert_eq!(Preset::Full.fields().0.len(), Field::letters().split(' ').count());
+        let mut table = vec!["mtr to example (192.0.2.1)".to_string(), "header".to_string()];
+        label_preset(&mut table, Some(Preset::Jitter));
+        assert_eq!(table[0], "mtr to example (192.0.2.1)  [preset: jitter]");
+    }
+
+    #[test]
+    fn compare_marks_only_diverging_hops() {
+        let config = TraceConfig {
+            first_ttl: 1, max_ttl: 3, error_policy: ErrorPolicy::Lost, sample_cap: 0, window: 0, history: 0, buckets: false, max_unknown: 0, ttls: None,
+        };
+        let (a, b) = (Tracer::new("a", HOP_IP, config), Tracer::new("b", HOP_IP, config));
+        let mut left: Vec<HopStats> = (1..=3).map(HopStats::new).collect();
+        let mut right: Vec<HopStats> = (1..=3).map(HopStats::new).collect();
+        for hops in [&mut left, &mut right] { hops[0].record_response(HOP_IP, 5000); }
+        left[1].record_response(HOP_IP, 10_000);
+        right[1].record_response(HOP_IP, 45_000);
+        left[2].record_response(HOP_IP, 10_000);
+        let fields = parse_fields("LA").unwrap();
+        let view = TableView { fields: &fields, no_dns: true, color: false, thresholds: THRESHOLDS, wide: false, multipath: false, summary: false, source: None, compare: true, diff_threshold: None };
+        let lines = render_compare((&a, &left), (&b, &right[..2]), view, |_, _| false);
+        let gutters: Vec<(usize, char)> = lines.iter()
+            .map(|line| line.chars().enumerate().find(|&(_, c)| c == '|' || c == '≠').unwrap())
+            .collect();
+        // Title and header, then hop 1 alike, hop 2 35 ms apart, hop 3 only on the left
+        assert!(gutters.iter().all(|&(column, _)| column == gutters[0].0));
+        assert_eq!(gutters.iter().map(|&(_, c)| c).collect::<String>(), "|||≠|");
+        assert!(lines[4].ends_with("| "));
+
+        // A 40 ms threshold no longer marks hop 2, and in color every hop row is dimmed
+        let diff_threshold = Some(parse_diff_threshold("5,40").unwrap());
+        let lines = render_compare((&a, &left), (&b, &right[..2]), TableView { color: true, diff_threshold, ..view }, |_, _| false);
+        assert!(lines.iter().all(|line| !line.contains('≠')));
+        assert!(lines[2..].iter().all(|line| line.starts_with("\x1B[2m")) && !lines[1].starts_with("\x1B[2m"));
+        assert!(parse_diff_threshold("10").is_err() && parse_diff_threshold("-1,5").is_err());
+    }
+
+    #[test]
+    fn cidr_sweeps_skip_only_unusable_addresses() {
+        let v4 = parse_cidr("192.0.2.0/24").unwrap();
+        assert_eq!((v4.len(), v4[0], v4[253]), (254, "192.0.2.1".parse().unwrap(), "192.0.2.254".parse().unwrap()));
+        // A /120 is 256 addresses less the Subnet-Router anycast one, which fits the cap
+        let v6 = parse_cidr("2001:db8::/120").unwrap();
+        assert_eq!((v6.len(), v6[0], v6[254]), (255, "2001:db8::1".parse().unwrap(), "2001:db8::ff".parse().unwrap()));
+        assert_eq!(parse_cidr("2001:db8::/127").unwrap().len(), 2);
+        assert!(parse_cidr("2001:db8::/119").unwrap_err().contains("too large"));
+    }
+
+    #[test]
+    fn html_chart_breaks_the_line_at_losses() {
+        let mut hop = HopStats::new(1).with_history(html::CHART_SAMPLES);
+        hop.record_response(HOP_IP, 10_000);
+        hop.record_response(HOP_IP, 20_000);
+        hop.record_timeout();
+        hop.record_response(HOP_IP, 5_000);
+        let svg = html::chart(&hop);
+        assert!(svg.contains("<polyline points=\"0.0,20.0 120.0,0.0\"/>"));
+        assert!(svg.contains("<line class=\"lost\" x1=\"240.0\""));
+        assert!(svg.contains("<polyline points=\"360.0,30.0\"/>"));
+    }
+
+    #[test]
+    fn html_chart_is_scaled_to_the_plotted_rtts() {
+        let mut hop = HopStats::new(1).with_window(1).with_history(html::CHART_SAMPLES);
+        hop.record_response(HOP_IP, 20_000);
+        hop.record_response(HOP_IP, 10_000);
+        // The window has forgotten the 20 ms reply, but the chart still plots it at the top
+        assert_eq!(hop.max_rtt, Some(10_000));
+        assert!(html::chart(&hop).contains("<polyline points=\"0.0,0.0 360.0,20.0\"/>"));
+    }
+
+    #[test]
+    fn html_row_shows_the_label_and_no_stdev_for_one_reply() {
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 10_000);
+        hop.label = Some("edge-fw".to_string());
+        let row = html::row(&hop);
+        assert!(row.contains("<td class=\"host\">edge-fw (192.0.2.1)</td>"));
+        assert!(row.contains("<td>10.0</td><td>-</td><td><svg"));
+    }
+
+    const THRESHOLDS: Thresholds = Thresholds { warn_loss: 0.0, crit_loss: 10.0, warn_latency: 100.0, crit_latency: 250.0 };
+
+    #[test]
+    fn thresholds_color_only_loss_and_avg_cells() {
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 150_000);
+        hop.record_timeout();
+        let fields = parse_fields("LAN").unwrap();
+        let line = format_hop(&hop, true, &fields, HOST_WIDTH, Some(THRESHOLDS));
+        assert!(line.contains("\x1B[31m 50.0%\x1B[39m"));
+        assert!(line.contains("\x1B[33m 150.0\x1B[39m"));
+        assert!(line.ends_with(" 150.0"));
+        let calm = Thresholds { crit_loss: 60.0, warn_latency: 200.0, ..THRESHOLDS };
+        let line = format_hop(&hop, true, &fields, HOST_WIDTH, Some(calm));
+        assert!(line.contains("\x1B[33m 50.0%") && line.contains("\x1B[32m 150.0"));
+    }
+
+    #[test]
+    fn clip_counts_only_visible_characters() {
+        assert_eq!(clip("abcdef", 4), "abcd");
+        assert_eq!(clip("ab", 4), "ab");
+        // Color codes cost no width, and the reset after the cut is kept
+        assert_eq!(clip("\x1B[31mabcdef\x1B[0m", 3), "\x1B[31mabc\x1B[0m");
+        assert_eq!(clip("é".repeat(10).as_str(), 2), "éé");
+    }
+
+    #[test]
+    fn recent_counts_replies_in_the_last_n_probes() {
+        let mut hop = HopStats::new(1).with_history(30);
+        for _ in 0..8 { hop.record_timeout(); }
+        for _ in 0..18 { hop.record_response(HOP_IP, 1000); }
+        hop.record_timeout();
+        hop.record_timeout();
+        assert_eq!(Field::Recent(20).cell(&hop), "18/20");
+        assert_eq!(Field::Recent(5).cell(&hop), " 3/5");
+        assert_eq!(sparkline(&hop, 20).chars().count(), 20);
+        assert_eq!(sparkline(&hop, 5), "▁▁▁  ");
+        hop.record_error(HOP_IP, 3000, ErrorPolicy::Lost);
+        assert_eq!(loss_pattern(&hop, 6), "...??!");
+        assert_eq!(Field::Recent(6).cell(&hop), " 3/6");
+    }
+
+    #[test]
+    fn sparkline_scales_between_best_and_worst_and_blanks_losses() {
+        let mut hop = HopStats::new(1).with_history(SPARKLINE_SAMPLES);
+        hop.record_response(HOP_IP, 10_000);
+        hop.record_timeout();
+        hop.record_response(HOP_IP, 45_000);
+        hop.record_response(HOP_IP, 80_000);
+        assert_eq!(sparkline(&hop, SPARKLINE_SAMPLES), "▁ ▅█");
+        let cell = Field::Sparkline(SPARKLINE_SAMPLES).cell(&hop);
+        assert_eq!(cell.chars().count(), SPARKLINE_SAMPLES);
+        assert!(cell.ends_with("▁ ▅█"));
+    }
+
+    #[test]
+    fn histogram_merges_buckets_from_the_fastest_reply() {
+        let mut hop = HopStats::new(1).with_buckets(true);
+        for ms in [10, 10, 11, 30] { hop.record_response(HOP_IP, ms * 1000 + 400); }
+        hop.record_timeout();
+        // 10..=30 ms is 21 buckets, merged two at a time to stay within 20 rows
+        let lines = histogram(&hop);
+        assert_eq!(lines.len(), 11);
+        assert_eq!(lines[0], format!("    10-12     ms |{} 3", "#".repeat(HISTOGRAM_BAR)));
+        assert!(lines[1].ends_with(" 0"));
+        assert!(lines[10].starts_with("    30-32     ms |##############  "));
+        assert_eq!(histogram(&HopStats::new(2).with_buckets(true)), vec!["  no replies"]);
+    }
+
+    #[test]
+    fn format_hop_truncates_multibyte_hostnames_on_char_boundaries() {
+        let mut hop = HopStats::new(1);
+        hop.record_response(HOP_IP, 1000);
+        // 'é' is two bytes, so byte 45 falls inside a character
+        hop.hostname = Some("é".repeat(40));
+        let line = format_hop(&hop, false, &parse_fields("L").unwrap(), HOST_WIDTH, None);
+        assert!(line.starts_with(&format!("  1. {} (192 ", "é".repeat(40))));
+        // --wide sizes the column to the whole name instead
+        let width = wide_host_width(std::slice::from_ref(&hop), false);
+        assert_eq!(width, 40 + " (192.0.2.1)".len());
+        assert!(format_hop(&hop, false, &parse_fields("L").unwrap(), width, None).contains("(192.0.2.1)"));
+        assert_eq!(truncate_chars("aéb", 2), "aé");
+        assert_eq!(truncate_chars("aé", 5), "aé");
+    }
 }
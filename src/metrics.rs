@@ -0,0 +1,80 @@
+//! Prometheus text exposition of the hop tables, served over a minimal HTTP listener.
+
+use mtr::{us_to_ms, HopStats, Tracer};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Latest rendered /metrics body, replaced after every cycle
+pub type Exposition = Arc<Mutex<String>>;
+
+/// Serve `body` at /metrics on all interfaces at `port` from a background thread
+pub fn serve(port: u16) -> Result<Exposition, String> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).map_err(|e| format!("cannot listen on port {}: {}", port, e))?;
+    let body: Exposition = Arc::new(Mutex::new(String::new()));
+    let shared = body.clone();
+    thread::spawn(move || {
+        // Each connection gets its own thread, so a client that never sends a request only ties up that one
+        for stream in listener.incoming().flatten() {
+            let shared = shared.clone();
+            thread::spawn(move || respond(stream, &shared));
+        }
+    });
+    Ok(body)
+}
+
+/// How long a scraper may take to send its request line or read the response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answer one request: the exposition at /metrics, 404 anywhere else
+fn respond(stream: TcpStream, body: &Exposition) {
+    let _ = stream.set_read_timeout(Some(CLIENT_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(CLIENT_TIMEOUT));
+    let mut request_line = String::new();
+    if BufReader::new(&stream).read_line(&mut request_line).is_err() { return; }
+    let response = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => {
+            let body = body.lock().unwrap();
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    let _ = (&stream).write_all(response.as_bytes());
+}
+
+/// Escape a label value per the exposition format
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Render gauges and counters for every displayed hop of every target, labelled with `tag` if given
+pub fn render(traces: &[Tracer], tag: Option<&str>) -> String {
+    type Metric = (&'static str, &'static str, &'static str, fn(&HopStats) -> Option<f64>);
+    let metrics: [Metric; 5] = [
+        ("mtr_hop_loss_percent", "gauge", "Packet loss at the hop in percent", |h| Some(h.loss_percent())),
+        ("mtr_hop_rtt_avg_ms", "gauge", "Mean round-trip time to the hop in milliseconds", |h| (h.received > 0).then(|| h.avg_rtt())),
+        ("mtr_hop_rtt_last_ms", "gauge", "Most recent round-trip time to the hop in milliseconds", |h| h.last_rtt.map(us_to_ms)),
+        ("mtr_hop_sent_total", "counter", "Probes sent to the hop", |h| Some(h.sent as f64)),
+        ("mtr_hop_received_total", "counter", "Replies received from the hop", |h| Some(h.received as f64)),
+    ];
+    let snapshots: Vec<_> = traces.iter().map(|t| (t, t.visible_hops())).collect();
+    let tag = tag.map_or(String::new(), |tag| format!(",tag=\"{}\"", label(tag)));
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+        for (trace, hops) in &snapshots {
+            for hop in hops {
+                let value = value(hop);
+                let Some(value) = value else { continue };
+                let ip = hop.ip.map_or(String::new(), |ip| ip.to_string());
+                out.push_str(&format!(
+                    "{}{{target=\"{}\",target_ip=\"{}\",ttl=\"{}\",ip=\"{}\"{}}} {}\n",
+                    name, label(&trace.target), trace.target_ip, hop.ttl, ip, tag, value
+                ));
+            }
+        }
+    }
+    out
+}